@@ -0,0 +1,43 @@
+use crate::identity::MacAddress;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How long `DongleHandle::start_pairing` waits by default.
+pub const DEFAULT_PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Progress of Bluetooth pairing, as reported by the dongle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PairingEvent {
+    /// `BtPairStart` was sent
+    Started,
+    /// The name the dongle advertises, for picking it on the phone
+    DongleName(String),
+    /// The PIN the phone should show
+    Pin(String),
+    /// A phone connected over Bluetooth, whether paired now or before
+    Connected,
+    Disconnected,
+    Finished(PairingResult),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingResult {
+    Paired,
+    TimedOut,
+    Cancelled,
+}
+
+/// Which paired phone to connect, and where the firmware takes such requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BluetoothConfig {
+    /// Address of the phone to connect when several are paired
+    pub preferred_phone: Option<MacAddress>,
+    /// Dongle file the preferred address is written to. Firmware specific,
+    /// so it needs `expert`; choosing a phone is unsupported when unset.
+    pub preferred_phone_file: Option<String>,
+    /// Dongle file an address is written to for the dongle to forget it.
+    /// Firmware specific, so it needs `expert`; forgetting is unsupported
+    /// when unset.
+    pub forget_file: Option<String>,
+}
@@ -0,0 +1,138 @@
+use crate::sendable::{FileAddress, IconConfig, SendFile, SendIconConfig};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The icon files the dongle shows on the phone, and their square sizes.
+pub const ICON_FILES: [(FileAddress, u32); 4] = [
+    (FileAddress::OemIcon, 256),
+    (FileAddress::Icon120, 120),
+    (FileAddress::Icon180, 180),
+    (FileAddress::Icon250, 256),
+];
+
+#[derive(Debug, Error)]
+pub enum BrandingError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to convert {}: {source}", path.display())]
+    Image {
+        path: PathBuf,
+        #[source]
+        source: ImageError,
+    },
+}
+
+/// What the CarPlay "My Car" tile shows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrandingConfig {
+    /// PNG scaled and cropped to every icon size, the dongle's own icons
+    /// stay when unset
+    pub icon: Option<PathBuf>,
+    /// Text under the icon
+    pub label: Option<String>,
+    pub name: String,
+    pub model: String,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        let icon_config = IconConfig::default();
+        Self {
+            icon: None,
+            label: icon_config.label,
+            name: icon_config.name,
+            model: icon_config.model,
+        }
+    }
+}
+
+impl BrandingConfig {
+    pub fn icon_config(&self) -> IconConfig {
+        IconConfig {
+            label: self.label.clone(),
+            name: self.name.clone(),
+            model: self.model.clone(),
+        }
+    }
+}
+
+/// A `BrandingConfig` with its icon scaled, ready to upload.
+#[derive(Debug, Clone)]
+pub struct Branding {
+    icons: Vec<(FileAddress, Vec<u8>)>,
+    icon_config: IconConfig,
+}
+
+impl Branding {
+    /// Reads and scales the icon, which blocks; see `load_async`.
+    pub fn load(config: &BrandingConfig) -> Result<Self, BrandingError> {
+        let icons = match &config.icon {
+            Some(path) => scale_icon(path)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            icons,
+            icon_config: config.icon_config(),
+        })
+    }
+
+    /// `load` on the blocking thread pool, for use on the async runtime.
+    pub async fn load_async(config: &BrandingConfig) -> Result<Self, BrandingError> {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || Self::load(&config))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// The icon files first, then the airplay config that points at them.
+    pub fn files(&self) -> Vec<SendFile> {
+        let mut files: Vec<SendFile> = self
+            .icons
+            .iter()
+            .map(|(file, png)| SendFile::new(png.clone(), file.as_str().to_string()))
+            .collect();
+        files.push(SendIconConfig::new(self.icon_config.clone()).into());
+        files
+    }
+}
+
+/// Reads the image at `path`, failing the way uploading it would.
+pub fn decode_icon(path: &Path) -> Result<DynamicImage, BrandingError> {
+    let data = fs::read(path).map_err(|source| BrandingError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    image::load_from_memory(&data).map_err(|source| BrandingError::Image {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn scale_icon(path: &Path) -> Result<Vec<(FileAddress, Vec<u8>)>, BrandingError> {
+    let image_error = |source| BrandingError::Image {
+        path: path.to_path_buf(),
+        source,
+    };
+    let image = decode_icon(path)?;
+    ICON_FILES
+        .iter()
+        .map(|&(file, size)| {
+            let mut png = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(image_error)?;
+            Ok((file, png))
+        })
+        .collect()
+}
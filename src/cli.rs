@@ -0,0 +1,148 @@
+use crate::config::{load_config, ConfigError};
+use crate::driver::{DongleConfig, HandDriveType, MicType, WifiType};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Dongle configuration file (TOML, or JSON with a .json extension)
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Put the dongle in Bluetooth pairing mode after start-up, for first-time setup
+    #[arg(long)]
+    pub pair: bool,
+
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print what the dongle reports about itself and exit
+    Info {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Seconds to wait for the dongle
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Args {
+    /// Lays the config file, if one was given, over `default`, and applies
+    /// the command-line overrides on top.
+    pub fn dongle_config(&self, default: DongleConfig) -> Result<DongleConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => load_config(path, &default)?,
+            None => default,
+        };
+        self.overrides.apply(&mut config);
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Default, clap::Args)]
+pub struct ConfigOverrides {
+    /// Video resolution, e.g. 1920x1080
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_resolution)]
+    pub resolution: Option<(u32, u32)>,
+
+    /// Video frame rate
+    #[arg(long)]
+    pub fps: Option<u32>,
+
+    /// Screen density reported to the phone
+    #[arg(long)]
+    pub dpi: Option<u32>,
+
+    /// Name shown on the phone, at most 16 bytes
+    #[arg(long)]
+    pub box_name: Option<String>,
+
+    /// Wi-Fi band: 2.4ghz or 5ghz
+    #[arg(long, value_parser = parse_name::<WifiType>)]
+    pub wifi_band: Option<WifiType>,
+
+    /// Microphone: box or os
+    #[arg(long, value_parser = parse_name::<MicType>)]
+    pub mic: Option<MicType>,
+
+    /// Steering wheel side: lhd or rhd
+    #[arg(long, value_parser = parse_name::<HandDriveType>)]
+    pub hand_drive: Option<HandDriveType>,
+
+    /// Let the phone stream audio straight to the car instead of the dongle
+    #[arg(long)]
+    pub audio_transfer: Option<bool>,
+
+    /// Allow pushing files to any path on the dongle
+    #[arg(long)]
+    pub expert: bool,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut DongleConfig) {
+        if let Some((width, height)) = self.resolution {
+            config.width = width;
+            config.height = height;
+        }
+        if let Some(fps) = self.fps {
+            config.fps = fps;
+        }
+        if let Some(dpi) = self.dpi {
+            config.dpi = dpi;
+        }
+        if let Some(box_name) = &self.box_name {
+            config.box_name = box_name.clone();
+        }
+        if let Some(wifi_type) = self.wifi_band {
+            config.wifi_type = wifi_type;
+        }
+        if let Some(mic_type) = self.mic {
+            config.mic_type = mic_type;
+        }
+        if let Some(hand) = self.hand_drive {
+            config.hand = hand;
+        }
+        if let Some(audio_transfer_mode) = self.audio_transfer {
+            config.audio_transfer_mode = audio_transfer_mode;
+        }
+        if self.expert {
+            config.expert = true;
+        }
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{}`", value))?;
+    let width = width
+        .parse()
+        .map_err(|e| format!("invalid width `{}`: {}", width, e))?;
+    let height = height
+        .parse()
+        .map_err(|e| format!("invalid height `{}`: {}", height, e))?;
+    Ok((width, height))
+}
+
+/// Parses a value by the same name it has in config files, so both accept
+/// exactly the same spellings.
+fn parse_name<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    let deserializer: StrDeserializer<ValueError> = value.into_deserializer();
+    T::deserialize(deserializer).map_err(|e| e.to_string())
+}
@@ -1,10 +1,13 @@
+use serde::{Deserialize, Serialize};
 
 #[repr(u16)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CommandMapping {
     Invalid = 0,
     StartRecordAudio = 1, 
     StopRecordAudio = 2, 
+    #[serde(rename = "request_host_ui")]
     RequestHostUI = 3, // "My Car" button clicked in the Carplay interface
     Siri = 5, // Siri Button
     Mic = 7, // Car Microphone
@@ -0,0 +1,537 @@
+use crate::branding::decode_icon;
+use crate::driver::DongleConfig;
+use crate::filepush::check_path;
+use crate::message::HEADER_SIZE;
+use crate::messagetypes::PhoneType;
+use crate::nightmode::AutoNightMode;
+use crate::readable::VIDEO_HEADER_SIZE;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config in {}: {source}", path.display())]
+    Toml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid config in {}: {source}", path.display())]
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+/// Reads a `DongleConfig` from a `.json` file, or TOML for any other
+/// extension. Fields missing from the file keep their value in `base`, and
+/// `phone_config` profiles are merged field by field over those in `base`.
+pub fn load_config(path: &Path, base: &DongleConfig) -> Result<DongleConfig, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let json_error = |source| ConfigError::Json {
+        path: path.to_path_buf(),
+        source,
+    };
+    let toml_error = |source| ConfigError::Toml {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    // Parsing into DongleConfig first reports mistakes with their position
+    let file: Value = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str::<DongleConfig>(&contents).map_err(json_error)?;
+        serde_json::from_str(&contents).map_err(json_error)?
+    } else {
+        toml::from_str::<DongleConfig>(&contents).map_err(toml_error)?;
+        toml::from_str(&contents).map_err(toml_error)?
+    };
+    let mut merged = serde_json::to_value(base).map_err(json_error)?;
+    merge_config(&mut merged, file);
+    serde_json::from_value(merged).map_err(json_error)
+}
+
+/// Lays the top-level keys of `file` over `base`. Profiles are keyed by the
+/// phone type's name, which the file may also give as its number.
+fn merge_config(base: &mut Value, file: Value) {
+    let (Value::Object(base), Value::Object(file)) = (base, file) else {
+        return;
+    };
+    for (key, value) in file {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(profiles)), Value::Object(file_profiles))
+                if key == "phone_config" =>
+            {
+                for (phone_type, profile) in file_profiles {
+                    let phone_type = phone_type
+                        .parse::<PhoneType>()
+                        .map_or(phone_type, |phone_type| phone_type.to_string());
+                    match (profiles.get_mut(&phone_type), profile) {
+                        (Some(Value::Object(fields)), Value::Object(file_fields)) => {
+                            fields.extend(file_fields)
+                        }
+                        (_, profile) => {
+                            profiles.insert(phone_type, profile);
+                        }
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+const MAX_BOX_NAME_LENGTH: usize = 16;
+const MAX_FPS: u32 = 60;
+const MAX_MEDIA_DELAY: u32 = 2000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigIssue {
+    #[error("resolution {width}x{height} has a zero dimension")]
+    ZeroResolution { width: u32, height: u32 },
+    #[error("resolution {width}x{height} is not even, some decoders reject it")]
+    OddResolution { width: u32, height: u32 },
+    #[error("fps {0} is outside 1..={MAX_FPS}")]
+    FpsOutOfRange(u32),
+    #[error("dpi must be non-zero")]
+    ZeroDpi,
+    #[error("packet_max {packet_max} is smaller than a video frame header ({minimum} bytes)")]
+    PacketMaxTooSmall { packet_max: u32, minimum: u32 },
+    #[error("box_name is {0} bytes, the dongle accepts at most {MAX_BOX_NAME_LENGTH}")]
+    BoxNameTooLong(usize),
+    #[error("box_name is empty")]
+    EmptyBoxName,
+    #[error("media_delay {0} ms is longer than {MAX_MEDIA_DELAY} ms")]
+    MediaDelayTooLong(u32),
+    #[error("fps {0} in the Android Auto profile is ignored, Android Auto keeps the open fps")]
+    AndroidAutoFps(u32),
+    #[error("frame_interval for {0:?} must be non-zero")]
+    ZeroFrameInterval(PhoneType),
+    #[error("light sensor dark_below {dark_below} is above light_above {light_above}")]
+    LightThresholdsInverted { dark_below: u32, light_above: u32 },
+    #[error("light sensor poll_interval must be non-zero")]
+    ZeroPollInterval,
+    #[error("time_sync_interval must be non-zero")]
+    ZeroTimeSyncInterval,
+    #[error("temperature warning {warning} °C is above critical {critical} °C")]
+    TemperatureThresholdsInverted { warning: u32, critical: u32 },
+    #[error("branding {0} must be a single line")]
+    MultilineBranding(&'static str),
+    #[error("{0}, the dongle keeps its own icons")]
+    UnusableBrandingIcon(String),
+    #[error("{0} {1} is not a known dongle file, writing it needs expert")]
+    NeedsExpert(&'static str, String),
+}
+
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl DongleConfig {
+    /// Checks the config, including every phone type profile, for values the
+    /// dongle rejects or misbehaves with. Errors make `DongleDriver::start`
+    /// refuse the config, warnings are only logged.
+    pub fn validate(&self) -> Validation {
+        let mut validation = Validation::default();
+
+        check_session(self, &mut validation);
+        for (phone_type, phone_config) in &self.phone_config {
+            check_session(&self.for_phone(*phone_type), &mut validation);
+            if phone_config.frame_interval == Some(0) {
+                validation
+                    .errors
+                    .push(ConfigIssue::ZeroFrameInterval(*phone_type));
+            }
+            if let (PhoneType::AndroidAuto, Some(fps)) = (phone_type, phone_config.fps) {
+                validation.warnings.push(ConfigIssue::AndroidAutoFps(fps));
+            }
+        }
+
+        let minimum = (HEADER_SIZE + VIDEO_HEADER_SIZE) as u32;
+        if self.packet_max < minimum {
+            validation.errors.push(ConfigIssue::PacketMaxTooSmall {
+                packet_max: self.packet_max,
+                minimum,
+            });
+        }
+
+        if self.box_name.len() > MAX_BOX_NAME_LENGTH {
+            validation
+                .errors
+                .push(ConfigIssue::BoxNameTooLong(self.box_name.len()));
+        } else if self.box_name.is_empty() {
+            validation.warnings.push(ConfigIssue::EmptyBoxName);
+        }
+
+        if let Some(AutoNightMode::LightSensor {
+            dark_below,
+            light_above,
+            poll_interval,
+            ..
+        }) = self.auto_night_mode
+        {
+            if dark_below > light_above {
+                validation
+                    .errors
+                    .push(ConfigIssue::LightThresholdsInverted {
+                        dark_below,
+                        light_above,
+                    });
+            }
+            if poll_interval == 0 {
+                validation.errors.push(ConfigIssue::ZeroPollInterval);
+            }
+        }
+
+        if self.time_sync_interval == Some(0) {
+            validation.errors.push(ConfigIssue::ZeroTimeSyncInterval);
+        }
+        if self.temperature.warning > self.temperature.critical {
+            validation
+                .errors
+                .push(ConfigIssue::TemperatureThresholdsInverted {
+                    warning: self.temperature.warning,
+                    critical: self.temperature.critical,
+                });
+        }
+        if !self.expert {
+            for (field, path) in [
+                ("preferred_phone_file", &self.bluetooth.preferred_phone_file),
+                ("forget_file", &self.bluetooth.forget_file),
+            ] {
+                if let Some(path) = path
+                    .as_ref()
+                    .filter(|path| check_path(path, false).is_err())
+                {
+                    validation
+                        .errors
+                        .push(ConfigIssue::NeedsExpert(field, path.clone()));
+                }
+            }
+        }
+        if let Some(branding) = &self.branding {
+            // Each airplay config entry is one line
+            for (field, value) in [
+                ("label", branding.label.as_deref().unwrap_or_default()),
+                ("name", branding.name.as_str()),
+                ("model", branding.model.as_str()),
+            ] {
+                if value.contains(['\n', '\r']) {
+                    validation
+                        .errors
+                        .push(ConfigIssue::MultilineBranding(field));
+                }
+            }
+            if let Some(Err(e)) = branding.icon.as_deref().map(decode_icon) {
+                validation
+                    .warnings
+                    .push(ConfigIssue::UnusableBrandingIcon(e.to_string()));
+            }
+        }
+
+        validation
+    }
+}
+
+/// Checks the settings a phone type profile can override. Profiles that
+/// inherit a bad top-level value report it only once.
+fn check_session(config: &DongleConfig, validation: &mut Validation) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    if config.width == 0 || config.height == 0 {
+        errors.push(ConfigIssue::ZeroResolution {
+            width: config.width,
+            height: config.height,
+        });
+    } else if !config.width.is_multiple_of(2) || !config.height.is_multiple_of(2) {
+        warnings.push(ConfigIssue::OddResolution {
+            width: config.width,
+            height: config.height,
+        });
+    }
+    if config.fps == 0 || config.fps > MAX_FPS {
+        errors.push(ConfigIssue::FpsOutOfRange(config.fps));
+    }
+    if config.dpi == 0 {
+        errors.push(ConfigIssue::ZeroDpi);
+    }
+    if config.media_delay > MAX_MEDIA_DELAY {
+        warnings.push(ConfigIssue::MediaDelayTooLong(config.media_delay));
+    }
+
+    for issue in errors {
+        if !validation.errors.contains(&issue) {
+            validation.errors.push(issue);
+        }
+    }
+    for issue in warnings {
+        if !validation.warnings.contains(&issue) {
+            validation.warnings.push(issue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::branding::BrandingConfig;
+    use crate::driver::PhoneTypeConfig;
+
+    fn base() -> DongleConfig {
+        DongleConfig {
+            box_name: "base".to_string(),
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            ..Default::default()
+        }
+    }
+
+    /// Loads `contents` from a temporary file called `file_name`.
+    fn load(file_name: &str, contents: &str) -> Result<DongleConfig, ConfigError> {
+        let path =
+            std::env::temp_dir().join(format!("rust-carplay-{}-{}", std::process::id(), file_name));
+        fs::write(&path, contents).unwrap();
+        load_config(&path, &base())
+    }
+
+    #[test]
+    fn missing_fields_keep_the_base_values() {
+        let config = load("partial.toml", "box_name = \"car\"\n").unwrap();
+        assert_eq!(config.box_name, "car");
+        assert_eq!((config.width, config.height, config.fps), (1920, 1080, 60));
+
+        let config = load("partial.json", r#"{"fps": 30}"#).unwrap();
+        assert_eq!(config.box_name, "base");
+        assert_eq!(config.fps, 30);
+    }
+
+    #[test]
+    fn profiles_merge_over_the_base_profiles() {
+        let config = load(
+            "profiles.toml",
+            "[phone_config.androidauto]\nwidth = 1280\n\n[phone_config.3]\ndpi = 200\n",
+        )
+        .unwrap();
+        let carplay = &config.phone_config[&PhoneType::CarPlay];
+        assert_eq!(carplay.frame_interval, Some(5000));
+        assert_eq!(carplay.dpi, Some(200));
+        assert_eq!(
+            config.phone_config[&PhoneType::AndroidAuto].width,
+            Some(1280)
+        );
+        assert!(config.phone_config.contains_key(&PhoneType::HiCar));
+    }
+
+    #[test]
+    fn the_example_file_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("dongle.example.toml");
+        let config = load_config(&path, &base()).unwrap();
+        assert!(config.validate().is_valid());
+    }
+
+    #[test]
+    fn mistakes_are_still_reported() {
+        assert!(matches!(
+            load("unknown.toml", "no_such_field = 1\n"),
+            Err(ConfigError::Toml { .. })
+        ));
+        assert!(matches!(
+            load(
+                "unknown.json",
+                r#"{"phone_config": {"carplay": {"fps": "x"}}}"#
+            ),
+            Err(ConfigError::Json { .. })
+        ));
+    }
+
+    fn light_sensor(dark_below: u32, light_above: u32, poll_interval: u32) -> AutoNightMode {
+        AutoNightMode::LightSensor {
+            sysfs_root: PathBuf::from("/sys"),
+            device: None,
+            dark_below,
+            light_above,
+            poll_interval,
+        }
+    }
+
+    #[test]
+    fn validate_reports_each_issue() {
+        use ConfigIssue::*;
+        type Case = (fn(&mut DongleConfig), Vec<ConfigIssue>, Vec<ConfigIssue>);
+        let cases: Vec<Case> = vec![
+            (|_| {}, vec![], vec![]),
+            (
+                |c| c.width = 0,
+                vec![ZeroResolution {
+                    width: 0,
+                    height: 1080,
+                }],
+                vec![],
+            ),
+            (
+                |c| c.height = 1081,
+                vec![],
+                vec![OddResolution {
+                    width: 1920,
+                    height: 1081,
+                }],
+            ),
+            (|c| c.fps = 0, vec![FpsOutOfRange(0)], vec![]),
+            (|c| c.fps = MAX_FPS, vec![], vec![]),
+            (|c| c.fps = MAX_FPS + 1, vec![FpsOutOfRange(61)], vec![]),
+            (|c| c.dpi = 0, vec![ZeroDpi], vec![]),
+            (
+                |c| c.media_delay = MAX_MEDIA_DELAY + 1,
+                vec![],
+                vec![MediaDelayTooLong(2001)],
+            ),
+            (
+                |c| c.packet_max = 35,
+                vec![PacketMaxTooSmall {
+                    packet_max: 35,
+                    minimum: 36,
+                }],
+                vec![],
+            ),
+            (|c| c.packet_max = 36, vec![], vec![]),
+            (
+                |c| c.box_name = "x".repeat(MAX_BOX_NAME_LENGTH),
+                vec![],
+                vec![],
+            ),
+            (
+                |c| c.box_name = "x".repeat(MAX_BOX_NAME_LENGTH + 1),
+                vec![BoxNameTooLong(17)],
+                vec![],
+            ),
+            (|c| c.box_name.clear(), vec![], vec![EmptyBoxName]),
+            (
+                |c| c.auto_night_mode = Some(light_sensor(50, 10, 1000)),
+                vec![LightThresholdsInverted {
+                    dark_below: 50,
+                    light_above: 10,
+                }],
+                vec![],
+            ),
+            (
+                |c| c.auto_night_mode = Some(light_sensor(10, 10, 0)),
+                vec![ZeroPollInterval],
+                vec![],
+            ),
+            (
+                |c| c.time_sync_interval = Some(0),
+                vec![ZeroTimeSyncInterval],
+                vec![],
+            ),
+            (
+                |c| c.temperature.warning = c.temperature.critical + 1,
+                vec![TemperatureThresholdsInverted {
+                    warning: 91,
+                    critical: 90,
+                }],
+                vec![],
+            ),
+            (
+                |c| {
+                    c.phone_config
+                        .get_mut(&PhoneType::HiCar)
+                        .unwrap()
+                        .frame_interval = Some(0)
+                },
+                vec![ZeroFrameInterval(PhoneType::HiCar)],
+                vec![],
+            ),
+            (
+                |c| c.phone_config.get_mut(&PhoneType::AndroidAuto).unwrap().fps = Some(30),
+                vec![],
+                vec![AndroidAutoFps(30)],
+            ),
+        ];
+
+        for (i, (change, errors, warnings)) in cases.into_iter().enumerate() {
+            let mut config = base();
+            change(&mut config);
+            let validation = config.validate();
+            assert_eq!(validation.errors, errors, "case {}", i);
+            assert_eq!(validation.warnings, warnings, "case {}", i);
+            assert_eq!(validation.is_valid(), errors.is_empty(), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn profile_issues_are_reported_once() {
+        // Every profile inherits the bad top-level value
+        let config = DongleConfig { dpi: 0, ..base() };
+        assert_eq!(config.validate().errors, vec![ConfigIssue::ZeroDpi]);
+
+        let mut config = base();
+        for phone_type in [PhoneType::AndroidMirror, PhoneType::HiCar] {
+            config.phone_config.insert(
+                phone_type,
+                PhoneTypeConfig {
+                    fps: Some(0),
+                    width: Some(801),
+                    ..Default::default()
+                },
+            );
+        }
+        let validation = config.validate();
+        assert_eq!(validation.errors, vec![ConfigIssue::FpsOutOfRange(0)]);
+        assert_eq!(
+            validation.warnings,
+            vec![ConfigIssue::OddResolution {
+                width: 801,
+                height: 1080,
+            }]
+        );
+    }
+
+    #[test]
+    fn unusable_branding_icons_are_warned_about() {
+        let dir = std::env::temp_dir();
+        let garbage = dir.join(format!("rust-carplay-{}-garbage.png", std::process::id()));
+        fs::write(&garbage, "not a png").unwrap();
+        let icon = dir.join(format!("rust-carplay-{}-icon.png", std::process::id()));
+        image::RgbImage::new(4, 4).save(&icon).unwrap();
+
+        let warnings = |icon: &Path| {
+            DongleConfig {
+                branding: Some(BrandingConfig {
+                    icon: Some(icon.to_path_buf()),
+                    ..Default::default()
+                }),
+                ..base()
+            }
+            .validate()
+            .warnings
+        };
+        for path in [dir.join("rust-carplay-no-such-icon.png"), garbage] {
+            assert!(
+                matches!(warnings(&path)[..], [ConfigIssue::UnusableBrandingIcon(_)]),
+                "{}",
+                path.display()
+            );
+        }
+        assert!(warnings(&icon).is_empty());
+    }
+}
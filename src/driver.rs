@@ -1,20 +1,48 @@
-use crate::commands::CommandMapping::*;
+use crate::bluetooth::{BluetoothConfig, PairingEvent, PairingResult};
+use crate::branding::{Branding, BrandingConfig, BrandingError};
+use crate::commands::CommandMapping;
+use crate::commands::CommandMapping::{
+    BtPairStart, DisableNightMode, EnableNightMode, Frame, WifiConnect,
+};
+use crate::config::ConfigIssue;
+use crate::filepush::{check_path, FilePushReport, PushFile};
+use crate::identity::{ConnectedPhone, DongleIdentity, MacAddress};
 use crate::message::{Message, MessageHeader};
-use crate::sendable::SendableMessage;
+use crate::messagetypes::{BoxVersion, PhoneType, PhoneWorkMode, VideoFormat};
+use crate::negotiation::{NegotiatedParams, OverriddenField, VideoParams};
+use crate::nightmode::{run_auto_night_mode, AutoNightMode};
+use crate::readable::{BoxSettings, PairedDevice, Plugged};
+use crate::scheduler::{Scheduler, TaskInfo};
+use crate::sendable::{HeartBeat, SendBoxSettings, SendCommand, SendFile, SendableMessage};
+use crate::startup::{default_init_sequence, profile_actions, InitAction, InitStep};
+use crate::temperature::{
+    TemperatureConfig, TemperatureLevel, TemperatureMonitor, TemperatureReading,
+};
+use crate::timesync::{run_time_sync, unix_millis};
+use crate::wifi::{WifiEvent, WifiRetry, WifiRetryConfig};
 use log::{error, info, warn};
 use nusb;
-use nusb::transfer::{Direction, RequestBuffer};
-use nusb::{Device, Interface};
+use nusb::descriptors::{ActiveConfigurationError, Configuration};
+use nusb::transfer::{Direction, EndpointType, RequestBuffer};
+use nusb::{Device, DeviceInfo, Interface};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+use tokio::sync::broadcast;
 use tokio::sync::broadcast::Sender;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Receiver;
 use tokio::time;
 
 const HEADER_DATA_LENGTH: usize = 16;
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const FRAME_TASK: &str = "frame";
+const WIFI_RETRY_TASK: &str = "wifi_retry";
+const PAIRING_TIMEOUT_TASK: &str = "pairing_timeout";
 
 #[derive(Debug, Error)]
 pub enum DriverError {
@@ -22,39 +50,69 @@ pub enum DriverError {
     UsbError(#[from] nusb::Error),
     #[error("USB error: {0}")]
     UsbTransferError(#[from] nusb::transfer::TransferError),
+    #[error("USB configuration error: {0}")]
+    UsbConfigurationError(#[from] ActiveConfigurationError),
+    #[error("No interface with bulk IN and OUT endpoints found")]
+    NoMatchingInterface,
+    #[error("Interface has no bulk IN endpoint")]
+    MissingBulkIn,
+    #[error("Interface has no bulk OUT endpoint")]
+    MissingBulkOut,
+    #[error("Protocol violation: {0}")]
+    ProtocolViolation(String),
+    #[error("Send queue closed")]
+    SendQueueClosed,
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Driver not started")]
+    NotStarted,
+    #[error("Not supported by this dongle: {0}")]
+    Unsupported(&'static str),
+    #[error("Writing {0} needs expert mode")]
+    ExpertOnly(String),
+    #[error("Send failed: {0}")]
+    SendFailed(String),
+    #[error("Branding error: {0}")]
+    Branding(#[from] BrandingError),
+    #[error("Invalid config: {}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", "))]
+    InvalidConfig(Vec<ConfigIssue>),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum HandDriveType {
     Lhd = 0,
     Rhd = 1,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum PhoneType {
-    AndroidMirror = 1,
-    CarPlay = 3,
-    IphoneMirror = 4,
-    AndroidAuto = 5,
-    HiCar = 6,
-}
-
-#[derive(Debug, Clone)]
+/// Settings used while a phone of one type is connected. Anything left unset
+/// falls back to the top-level `DongleConfig` value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct PhoneTypeConfig {
     pub frame_interval: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Not applied to Android Auto, which keeps the fps of the last `Open`
+    pub fps: Option<u32>,
+    pub dpi: Option<u32>,
+    pub audio_transfer_mode: Option<bool>,
+    pub mic_type: Option<MicType>,
+    pub media_delay: Option<u32>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DongleConfig {
     pub android_work_mode: Option<bool>,
     pub width: u32,
     pub height: u32,
     pub fps: u32,
     pub dpi: u32,
-    pub format: u32,
-    pub i_box_version: u32,
+    pub format: VideoFormat,
+    pub i_box_version: BoxVersion,
     pub packet_max: u32,
-    pub phone_work_mode: u32,
+    pub phone_work_mode: PhoneWorkMode,
     pub night_mode: bool,
     pub box_name: String,
     pub hand: HandDriveType,
@@ -63,15 +121,87 @@ pub struct DongleConfig {
     pub wifi_type: WifiType,
     pub mic_type: MicType,
     pub phone_config: HashMap<PhoneType, PhoneTypeConfig>,
+    pub charge_mode: bool,
+    /// Milliseconds between the rest of the start-up and `WifiConnect`
+    pub wifi_connect_delay: u32,
+    /// Replaces the default start-up sequence when set
+    pub init_sequence: Option<Vec<InitStep>>,
+    /// Sent after the start-up sequence
+    pub extra_init_steps: Vec<InitStep>,
+    /// Switches night mode by itself when set, `night_mode` is the start value
+    pub auto_night_mode: Option<AutoNightMode>,
+    /// Milliseconds between time resyncs, on top of those after clock jumps
+    pub time_sync_interval: Option<u32>,
+    pub wifi_retry: WifiRetryConfig,
+    pub bluetooth: BluetoothConfig,
+    pub temperature: TemperatureConfig,
+    /// Uploaded by the `branding` start-up step when set
+    pub branding: Option<BrandingConfig>,
+    /// Allows pushing files to paths other than the known `FileAddress` ones
+    pub expert: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl DongleConfig {
+    /// The video size to use while a phone of this type is connected.
+    pub fn video_size(&self, phone_type: PhoneType) -> (u32, u32) {
+        let phone_config = self.phone_config.get(&phone_type);
+        (
+            phone_config
+                .and_then(|phone_config| phone_config.width)
+                .unwrap_or(self.width),
+            phone_config
+                .and_then(|phone_config| phone_config.height)
+                .unwrap_or(self.height),
+        )
+    }
+
+    /// This config with the profile for `phone_type` laid over it.
+    pub fn for_phone(&self, phone_type: PhoneType) -> DongleConfig {
+        let mut config = self.clone();
+        if let Some(profile) = self.phone_config.get(&phone_type) {
+            config.width = profile.width.unwrap_or(self.width);
+            config.height = profile.height.unwrap_or(self.height);
+            config.fps = profile.fps.unwrap_or(self.fps);
+            config.dpi = profile.dpi.unwrap_or(self.dpi);
+            config.audio_transfer_mode = profile
+                .audio_transfer_mode
+                .unwrap_or(self.audio_transfer_mode);
+            config.mic_type = profile.mic_type.unwrap_or(self.mic_type);
+            config.media_delay = profile.media_delay.unwrap_or(self.media_delay);
+        }
+        config
+    }
+
+    pub fn init_steps(&self) -> Vec<InitStep> {
+        let mut steps = self
+            .init_sequence
+            .clone()
+            .unwrap_or_else(|| default_init_sequence(self));
+        steps.extend(self.extra_init_steps.iter().cloned());
+        steps
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WifiType {
+    #[serde(rename = "2.4ghz")]
     Ghz2_4,
+    #[serde(rename = "5ghz")]
     Ghz5,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl WifiType {
+    /// The command that selects this band.
+    pub fn command(self) -> CommandMapping {
+        match self {
+            WifiType::Ghz5 => CommandMapping::Wifi5g,
+            WifiType::Ghz2_4 => CommandMapping::Wifi24g,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MicType {
     Box,
     Os,
@@ -84,14 +214,17 @@ impl Default for DongleConfig {
             PhoneType::CarPlay,
             PhoneTypeConfig {
                 frame_interval: Some(5000),
+                ..Default::default()
             },
         );
-        phone_config.insert(
+        for phone_type in [
+            PhoneType::AndroidMirror,
+            PhoneType::IphoneMirror,
             PhoneType::AndroidAuto,
-            PhoneTypeConfig {
-                frame_interval: None,
-            },
-        );
+            PhoneType::HiCar,
+        ] {
+            phone_config.insert(phone_type, PhoneTypeConfig::default());
+        }
 
         Self {
             android_work_mode: None,
@@ -99,9 +232,9 @@ impl Default for DongleConfig {
             height: 640,
             fps: 20,
             dpi: 160,
-            format: 5,
-            i_box_version: 2,
-            phone_work_mode: 2,
+            format: VideoFormat::H264,
+            i_box_version: BoxVersion::V2,
+            phone_work_mode: PhoneWorkMode::Standard,
             packet_max: 49152,
             box_name: "nodePlay".to_string(),
             night_mode: false,
@@ -111,6 +244,17 @@ impl Default for DongleConfig {
             wifi_type: WifiType::Ghz5,
             mic_type: MicType::Os,
             phone_config,
+            charge_mode: true,
+            wifi_connect_delay: 1000,
+            init_sequence: None,
+            extra_init_steps: Vec::new(),
+            auto_night_mode: None,
+            time_sync_interval: None,
+            wifi_retry: WifiRetryConfig::default(),
+            bluetooth: BluetoothConfig::default(),
+            temperature: TemperatureConfig::default(),
+            branding: None,
+            expert: false,
         }
     }
 }
@@ -132,13 +276,300 @@ pub const KNOWN_DEVICES: [KnownDevice; 2] = [
     },
 ];
 
+/// Things the driver works out from the dongle's messages, for the application.
+#[derive(Debug, Clone)]
+pub enum DriverEvent {
+    Negotiated {
+        params: NegotiatedParams,
+        overridden: Vec<OverriddenField>,
+    },
+    /// The stream the video pipeline and touch mapping should expect from now on
+    VideoParamsChanged(VideoParams),
+    NightModeChanged(bool),
+    Wifi(WifiEvent),
+    Pairing(PairingEvent),
+    PairedDevicesChanged(Vec<PairedDevice>),
+    /// Everything in `DongleIdentity` has arrived, or changed since
+    Identified(DongleIdentity),
+    /// A phone connected, told more about itself, or went away
+    ConnectedPhoneChanged(Option<ConnectedPhone>),
+    /// The link HiCar phones scan to connect, `None` once a HiCar phone has
+    HiCarLinkChanged(Option<String>),
+    /// The dongle's CPU temperature crossed a threshold, either way
+    TemperatureLevelChanged {
+        level: TemperatureLevel,
+        celsius: f64,
+    },
+}
+
+#[derive(Debug, Default)]
+struct DriverState {
+    config: Option<DongleConfig>,
+    /// `config` with the profile of the connected phone type applied
+    applied: Option<DongleConfig>,
+    negotiated: Option<NegotiatedParams>,
+    night_mode: bool,
+    /// Milliseconds to add to the system clock, from `DongleHandle::set_time`
+    clock_offset: i64,
+    pairing: bool,
+    paired_devices: Vec<PairedDevice>,
+    identity: DongleIdentity,
+    temperature: TemperatureMonitor,
+    connected_phone: Option<ConnectedPhone>,
+    hicar_link: Option<String>,
+}
+
+/// Cheap to clone access to a started driver, for tasks outside of it.
+#[derive(Clone)]
+pub struct DongleHandle {
+    message_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+    state: Arc<Mutex<DriverState>>,
+    events: Sender<DriverEvent>,
+    scheduler: Scheduler,
+}
+
+impl DongleHandle {
+    pub async fn send(&self, message: Box<dyn SendableMessage + Send>) -> Result<(), DriverError> {
+        self.message_tx
+            .send(message)
+            .await
+            .map_err(|_| DriverError::SendQueueClosed)
+    }
+
+    pub fn night_mode(&self) -> bool {
+        self.state.lock().unwrap().night_mode
+    }
+
+    pub async fn set_night_mode(&self, enabled: bool) -> Result<(), DriverError> {
+        let value = if enabled {
+            EnableNightMode
+        } else {
+            DisableNightMode
+        };
+        self.send(Box::new(SendCommand { value })).await?;
+        self.state.lock().unwrap().night_mode = enabled;
+        // Nobody listening is fine
+        let _ = self.events.send(DriverEvent::NightModeChanged(enabled));
+        Ok(())
+    }
+
+    /// Sends the current time to the dongle, with any offset from `set_time`.
+    pub async fn sync_time(&self) -> Result<(), DriverError> {
+        let (config, clock_offset) = {
+            let state = self.state.lock().unwrap();
+            let config = state.applied.clone().ok_or(DriverError::NotStarted)?;
+            (config, state.clock_offset)
+        };
+        let time = unix_millis(SystemTime::now()) + clock_offset;
+        info!("Syncing dongle time to {} ms", time);
+        self.send(Box::new(SendBoxSettings::new(
+            config,
+            Some(time.max(0) as u64),
+        )))
+        .await
+    }
+
+    /// Uses `time` from an outside source, such as a GPS receiver, in place
+    /// of the system clock until the system clock itself jumps.
+    pub async fn set_time(&self, time: SystemTime) -> Result<(), DriverError> {
+        self.state.lock().unwrap().clock_offset =
+            unix_millis(time) - unix_millis(SystemTime::now());
+        self.sync_time().await
+    }
+
+    /// Puts the dongle in pairing mode. Progress and the result arrive as
+    /// `DriverEvent::Pairing`; pairing gives up after `timeout`.
+    pub async fn start_pairing(&self, timeout: Duration) -> Result<(), DriverError> {
+        self.send(Box::new(SendCommand { value: BtPairStart }))
+            .await?;
+        self.state.lock().unwrap().pairing = true;
+        let _ = self
+            .events
+            .send(DriverEvent::Pairing(PairingEvent::Started));
+
+        let handle = self.clone();
+        self.scheduler.spawn(PAIRING_TIMEOUT_TASK, async move {
+            time::sleep(timeout).await;
+            warn!("Bluetooth pairing timed out after {:?}", timeout);
+            handle.finish_pairing(PairingResult::TimedOut);
+        });
+        Ok(())
+    }
+
+    /// Stops waiting for a phone to pair.
+    pub fn cancel_pairing(&self) {
+        self.scheduler.cancel(PAIRING_TIMEOUT_TASK);
+        self.finish_pairing(PairingResult::Cancelled);
+    }
+
+    pub fn is_pairing(&self) -> bool {
+        self.state.lock().unwrap().pairing
+    }
+
+    fn finish_pairing(&self, result: PairingResult) {
+        let was_pairing = std::mem::replace(&mut self.state.lock().unwrap().pairing, false);
+        if was_pairing {
+            let _ = self
+                .events
+                .send(DriverEvent::Pairing(PairingEvent::Finished(result)));
+        }
+    }
+
+    /// What the dongle reported about itself, once all of it has arrived.
+    pub fn identity(&self) -> Option<DongleIdentity> {
+        let state = self.state.lock().unwrap();
+        state.identity.is_complete().then(|| state.identity.clone())
+    }
+
+    /// What the dongle reported about itself so far.
+    pub fn partial_identity(&self) -> DongleIdentity {
+        self.state.lock().unwrap().identity.clone()
+    }
+
+    pub fn connected_phone(&self) -> Option<ConnectedPhone> {
+        self.state.lock().unwrap().connected_phone.clone()
+    }
+
+    /// The link for HiCar phones to scan, while none is connected.
+    pub fn hicar_link(&self) -> Option<String> {
+        self.state.lock().unwrap().hicar_link.clone()
+    }
+
+    /// The dongle's last reported CPU temperature.
+    pub fn temperature(&self) -> Option<TemperatureReading> {
+        self.state.lock().unwrap().temperature.latest()
+    }
+
+    /// Recent CPU temperature readings, oldest first.
+    pub fn temperature_history(&self) -> Vec<TemperatureReading> {
+        self.state.lock().unwrap().temperature.history()
+    }
+
+    /// The phones the dongle last reported as paired.
+    pub fn paired_devices(&self) -> Vec<PairedDevice> {
+        self.state.lock().unwrap().paired_devices.clone()
+    }
+
+    /// Uploads the icons and airplay config of `config`.
+    pub async fn apply_branding(&self, config: &BrandingConfig) -> Result<(), DriverError> {
+        for file in Branding::load_async(config).await?.files() {
+            self.push_file(file).await?;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for dongle_config in [state.config.as_mut(), state.applied.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            dongle_config.branding = Some(config.clone());
+        }
+        Ok(())
+    }
+
+    pub fn preferred_phone(&self) -> Option<MacAddress> {
+        let state = self.state.lock().unwrap();
+        state.config.as_ref()?.bluetooth.preferred_phone
+    }
+
+    /// Makes the dongle connect `address` when several phones are paired.
+    pub async fn set_preferred_phone(&self, address: MacAddress) -> Result<(), DriverError> {
+        let path = self
+            .bluetooth_config()?
+            .preferred_phone_file
+            .ok_or(DriverError::Unsupported("choosing the preferred phone"))?;
+        self.push_string(&path, &address.to_string()).await?;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for config in [state.config.as_mut(), state.applied.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            config.bluetooth.preferred_phone = Some(address);
+        }
+        Ok(())
+    }
+
+    /// Removes `address` from the dongle's paired phones.
+    pub async fn forget_paired_device(&self, address: MacAddress) -> Result<(), DriverError> {
+        let path = self
+            .bluetooth_config()?
+            .forget_file
+            .ok_or(DriverError::Unsupported("forgetting paired phones"))?;
+        self.push_string(&path, &address.to_string()).await?;
+        let devices = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .paired_devices
+                .retain(|device| device.address != address);
+            state.paired_devices.clone()
+        };
+        let _ = self.events.send(DriverEvent::PairedDevicesChanged(devices));
+        Ok(())
+    }
+
+    /// Writes `file` to the dongle in transfers of at most the negotiated
+    /// `packet_max`, rounded down to whole USB packets, and waits for the send loop to report back. Paths other
+    /// than the known `FileAddress` ones need `expert` in the config.
+    pub async fn push_file(&self, file: SendFile) -> Result<FilePushReport, DriverError> {
+        let packet_max = {
+            let state = self.state.lock().unwrap();
+            let config = state.config.as_ref().ok_or(DriverError::NotStarted)?;
+            check_path(file.path(), config.expert)?;
+            state
+                .negotiated
+                .map_or(config.packet_max, |negotiated| negotiated.packet_max)
+        };
+        let (push, result) = PushFile::new(file, packet_max as usize);
+        self.send(Box::new(push)).await?;
+        let report = result
+            .await
+            .map_err(|_| DriverError::SendQueueClosed)?
+            .map_err(DriverError::SendFailed)?;
+        info!("Pushed {:?}", report);
+        Ok(report)
+    }
+
+    pub async fn push_number(&self, path: &str, value: u32) -> Result<FilePushReport, DriverError> {
+        self.push_file(SendFile::number(value, path)).await
+    }
+
+    pub async fn push_boolean(
+        &self,
+        path: &str,
+        value: bool,
+    ) -> Result<FilePushReport, DriverError> {
+        self.push_file(SendFile::boolean(value, path)).await
+    }
+
+    pub async fn push_string(
+        &self,
+        path: &str,
+        value: &str,
+    ) -> Result<FilePushReport, DriverError> {
+        self.push_file(SendFile::string(value, path)).await
+    }
+
+    fn bluetooth_config(&self) -> Result<BluetoothConfig, DriverError> {
+        let state = self.state.lock().unwrap();
+        let config = state.config.as_ref().ok_or(DriverError::NotStarted)?;
+        Ok(config.bluetooth.clone())
+    }
+
+    /// The system clock was set, so an offset from `set_time` is stale.
+    pub(crate) fn clock_jumped(&self) {
+        self.state.lock().unwrap().clock_offset = 0;
+    }
+}
+
 pub struct DongleDriver {
     device: Option<Device>,
     pub(crate) in_ep: Option<u8>,
     pub(crate) out_ep: Option<u8>,
     error_count: Arc<Mutex<u32>>,
     max_error_count: u32,
-    heartbeat_handle: Option<tokio::task::JoinHandle<()>>,
+    scheduler: Scheduler,
+    state: Arc<Mutex<DriverState>>,
+    events: Sender<DriverEvent>,
     pub(crate) interface: Option<Interface>,
 }
 
@@ -151,19 +582,80 @@ impl DongleDriver {
             out_ep: None,
             error_count: Arc::new(Mutex::new(0)),
             max_error_count: 5,
-            heartbeat_handle: None,
+            scheduler: Scheduler::new(),
+            state: Arc::new(Mutex::new(DriverState::default())),
+            events: broadcast::channel(64).0,
         }
     }
 
-    async fn reset_usb(&mut self) {
-        let mut device_info = nusb::list_devices()
-            .unwrap()
-            .find(|dev| dev.vendor_id() == 0x1314 && dev.product_id() == 0x1521);
+    pub fn subscribe(&self) -> broadcast::Receiver<DriverEvent> {
+        self.events.subscribe()
+    }
+
+    /// A handle that sends through `message_tx`, the queue given to `start`.
+    pub fn handle(
+        &self,
+        message_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+    ) -> DongleHandle {
+        DongleHandle {
+            message_tx,
+            state: self.state.clone(),
+            events: self.events.clone(),
+            scheduler: self.scheduler.clone(),
+        }
+    }
+
+    /// The background tasks of the running session, for debugging.
+    pub fn scheduled_tasks(&self) -> Vec<TaskInfo> {
+        self.scheduler.tasks()
+    }
+
+    /// What the dongle accepted from the last `SendOpen`, once it has replied.
+    pub fn negotiated(&self) -> Option<NegotiatedParams> {
+        self.state.lock().unwrap().negotiated
+    }
+
+    fn find_device() -> Result<Option<DeviceInfo>, DriverError> {
+        Ok(nusb::list_devices()?
+            .find(|dev| dev.vendor_id() == 0x1314 && dev.product_id() == 0x1521))
+    }
+
+    /// Picks the first interface whose default alt setting has both a bulk IN
+    /// and a bulk OUT endpoint. Returns (interface number, IN address, OUT address).
+    fn find_bulk_endpoints(config: &Configuration) -> Result<(u8, u8, u8), DriverError> {
+        let mut result = Err(DriverError::NoMatchingInterface);
+        for interface in config.interfaces() {
+            let Some(alt_settings) = interface.alt_settings().next() else {
+                continue;
+            };
+            let in_endpoint = alt_settings.endpoints().find(|e| {
+                e.direction() == Direction::In && e.transfer_type() == EndpointType::Bulk
+            });
+            let out_endpoint = alt_settings.endpoints().find(|e| {
+                e.direction() == Direction::Out && e.transfer_type() == EndpointType::Bulk
+            });
+            match (in_endpoint, out_endpoint) {
+                (Some(in_endpoint), Some(out_endpoint)) => {
+                    return Ok((
+                        interface.interface_number(),
+                        in_endpoint.address(),
+                        out_endpoint.address(),
+                    ));
+                }
+                (None, Some(_)) => result = Err(DriverError::MissingBulkIn),
+                (Some(_), None) => result = Err(DriverError::MissingBulkOut),
+                (None, None) => {}
+            }
+        }
+        result
+    }
+
+    async fn reset_usb(&mut self) -> Result<(), DriverError> {
         loop {
-            if device_info.is_some() {
-                match device_info.expect("Device disappeared?").open() {
+            if let Some(device_info) = Self::find_device()? {
+                match device_info.open() {
                     Ok(device) => match device.reset() {
-                        Ok(_) => return,
+                        Ok(_) => return Ok(()),
                         Err(e) => {
                             warn!("Failed to reset device, will retry. Error was: {}", e);
                         }
@@ -173,163 +665,141 @@ impl DongleDriver {
                     }
                 }
             }
-            device_info = nusb::list_devices()
-                .unwrap()
-                .find(|dev| dev.vendor_id() == 0x1314 && dev.product_id() == 0x1521);
+            time::sleep(DEVICE_POLL_INTERVAL).await;
         }
     }
 
     pub async fn initialize(&mut self) -> Result<(), DriverError> {
-        self.reset_usb().await;
-        let mut device_info = nusb::list_devices()?
-            .find(|dev| dev.vendor_id() == 0x1314 && dev.product_id() == 0x1521);
+        self.reset_usb().await?;
         loop {
-            if device_info.is_some() {
-                match device_info.expect("Device disappeared?").open() {
+            if let Some(device_info) = Self::find_device()? {
+                match device_info.open() {
                     Ok(device) => {
                         device.set_configuration(1)?;
-                        let config = device.active_configuration().unwrap();
-                        let interface = config.interfaces().next().unwrap();
-
-                        let alt_settings = interface.alt_settings().next().unwrap();
-                        let in_endpoint = alt_settings
-                            .endpoints()
-                            .find(|e| e.direction() == Direction::In)
-                            .unwrap();
-                        let out_endpoint = alt_settings
-                            .endpoints()
-                            .find(|e| e.direction() == Direction::Out)
-                            .unwrap();
-
-                        self.interface =
-                            Some(device.claim_interface(interface.interface_number())?);
+                        let config = device.active_configuration()?;
+                        let (interface_number, in_ep, out_ep) = Self::find_bulk_endpoints(&config)?;
+
+                        self.interface = Some(device.claim_interface(interface_number)?);
                         self.device = Some(device.clone());
-                        self.in_ep = Some(in_endpoint.address());
-                        self.out_ep = Some(out_endpoint.address());
-                        break;
+                        self.in_ep = Some(in_ep);
+                        self.out_ep = Some(out_ep);
+                        return Ok(());
                     }
                     Err(e) => {
                         warn!("Failed to connect to device, will retry. Error was: {}", e);
                     }
                 }
             }
-            device_info = nusb::list_devices()?
-                .find(|dev| dev.vendor_id() == 0x1314 && dev.product_id() == 0x1521);
+            time::sleep(DEVICE_POLL_INTERVAL).await;
         }
-
-        Ok(())
     }
 
     pub async fn start(
         &mut self,
         config: DongleConfig,
         message_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+        messages: broadcast::Receiver<Message>,
     ) -> Result<(), DriverError> {
-        *self.error_count.lock().unwrap() = 0;
-        use crate::sendable::*;
+        let validation = config.validate();
+        for warning in &validation.warnings {
+            warn!("Config: {}", warning);
+        }
+        if !validation.is_valid() {
+            return Err(DriverError::InvalidConfig(validation.errors));
+        }
 
-        message_tx
-            .send(Box::new(SendNumber::new(config.dpi, FileAddress::Dpi)))
-            .await
-            .unwrap();
+        // Steps up to the first delay are queued right away, the rest are
+        // sent in the background so the caller can start the USB loops.
+        // Building them first leaves a running session alone if one fails.
+        let mut steps = config.init_steps().into_iter().peekable();
+        let mut immediate = Vec::new();
+        while let Some(step) = steps.next_if(|step| step.delay == 0) {
+            immediate.extend(step.action.messages(&config).await?);
+        }
+        let remaining: Vec<InitStep> = steps.collect();
 
-        message_tx
-            .send(Box::new(SendOpen::new(config.clone())))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendBoolean::new(
-                config.night_mode,
-                FileAddress::NightMode,
-            )))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendNumber::new(
-                config.hand as u32,
-                FileAddress::HandDriveMode,
-            )))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendBoolean::new(true, FileAddress::ChargeMode)))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendString::new(
-                config.box_name.clone(),
-                FileAddress::BoxName,
-            )))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendBoxSettings::new(config.clone(), None)))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendCommand { value: WifiEnable }))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendCommand {
-                value: match config.wifi_type {
-                    WifiType::Ghz5 => Wifi5g,
-                    WifiType::Ghz2_4 => Wifi24g,
-                },
-            }))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendCommand {
-                value: match config.mic_type {
-                    MicType::Box => BoxMic,
-                    MicType::Os => Mic,
-                },
-            }))
-            .await
-            .unwrap();
-        message_tx
-            .send(Box::new(SendCommand {
-                value: if config.audio_transfer_mode {
-                    AudioTransferOn
-                } else {
-                    AudioTransferOff
-                },
-            }))
-            .await
-            .unwrap();
+        // Restarting replaces the previous session's tasks
+        self.scheduler.cancel_all();
+        *self.error_count.lock().unwrap() = 0;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.config = Some(config.clone());
+            state.applied = Some(config.clone());
+            state.negotiated = None;
+            state.night_mode = config.night_mode;
+            state.clock_offset = 0;
+            state.pairing = false;
+            state.paired_devices.clear();
+            state.identity = DongleIdentity::default();
+            state.temperature = TemperatureMonitor::new(config.temperature.clone());
+            state.connected_phone = None;
+            state.hicar_link = None;
+        }
+        let handler = MessageHandler {
+            dongle: self.handle(message_tx.clone()),
+            wifi: WifiRetry::new(config.wifi_retry.clone(), config.wifi_type),
+            identity: DongleIdentity::default(),
+        };
+        self.scheduler
+            .spawn("message_handler", handler.run(messages));
 
-        // Schedule Wi-Fi connect after delay
-        let tx = message_tx.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            tx.send(Box::new(SendCommand { value: WifiConnect }))
+        for message in immediate {
+            message_tx
+                .send(message)
                 .await
-                .unwrap();
-        });
+                .map_err(|_| DriverError::SendQueueClosed)?;
+        }
 
-        // Start heartbeat
-        let tx = message_tx.clone();
-        self.heartbeat_handle = Some(tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(2));
-            loop {
-                interval.tick().await;
-                match tx.send(Box::new(HeartBeat)).await {
-                    Ok(_) => {
-                        info!("Sent HeartBeat")
+        if !remaining.is_empty() {
+            let tx = message_tx.clone();
+            let config = config.clone();
+            self.scheduler.spawn("startup", async move {
+                for step in remaining {
+                    time::sleep(Duration::from_millis(step.delay as u64)).await;
+                    let messages = match step.action.messages(&config).await {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            error!("{:?} error: {}", step.action, e);
+                            continue;
+                        }
+                    };
+                    for message in messages {
+                        if tx.send(message).await.is_err() {
+                            error!("{:?} error: {}", step.action, DriverError::SendQueueClosed);
+                            return;
+                        }
                     }
-                    Err(e) => error!("HeartBeat error: {}", e),
                 }
-            }
-        }));
+            });
+        }
+
+        // The first heartbeat goes out at once, `every` waits a period
+        message_tx
+            .send(Box::new(HeartBeat))
+            .await
+            .map_err(|_| DriverError::SendQueueClosed)?;
+        self.scheduler
+            .every("heartbeat", HEARTBEAT_INTERVAL, message_tx.clone(), || {
+                Box::new(HeartBeat)
+            });
+        if let Some(source) = config.auto_night_mode.clone() {
+            let handle = self.handle(message_tx.clone());
+            self.scheduler
+                .spawn("auto_night_mode", run_auto_night_mode(source, handle));
+        }
+        let interval = config
+            .time_sync_interval
+            .map(|interval| Duration::from_millis(interval as u64));
+        self.scheduler.spawn(
+            "time_sync",
+            run_time_sync(self.handle(message_tx.clone()), interval),
+        );
 
         Ok(())
     }
 
     pub async fn close(&mut self) -> Result<(), DriverError> {
-        if let Some(handle) = self.heartbeat_handle.take() {
-            handle.abort();
-        }
+        self.scheduler.cancel_all();
 
         self.device = None;
         self.in_ep = None;
@@ -339,29 +809,429 @@ impl DongleDriver {
     }
 }
 
+struct MessageHandler {
+    dongle: DongleHandle,
+    wifi: WifiRetry,
+    identity: DongleIdentity,
+}
+
+impl MessageHandler {
+    async fn run(mut self, mut messages: broadcast::Receiver<Message>) {
+        loop {
+            match messages.recv().await {
+                Ok(message) => self.handle(&message).await,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!("Message handler skipped {} messages", count);
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    // Not on drop, an aborted handler may be dropped after
+                    // the next session has scheduled its own frame task
+                    self.stop_frame_requests();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: &Message) {
+        self.update_identity(message);
+        match message {
+            Message::ReadOpen(opened) => self.on_opened(NegotiatedParams::from(opened)),
+            Message::ReadPlugged(plugged) => self.on_plugged(plugged).await,
+            Message::ReadUnplugged(_) => {
+                self.stop_frame_requests();
+                self.set_connected_phone(None);
+            }
+            Message::ReadCommand(command) => self.on_command(command.value).await,
+            Message::ReadBluetoothPIN(pin) => self.on_pairing(PairingEvent::Pin(pin.pin.clone())),
+            Message::ReadBluetoothDeviceName(name) => {
+                self.on_pairing(PairingEvent::DongleName(name.name.clone()))
+            }
+            Message::ReadBluetoothPairedList(list) => self.on_paired_list(&list.devices),
+            Message::ReadHiCarLink(link) if !link.link.is_empty() => {
+                self.set_hicar_link(Some(link.link.clone()))
+            }
+            Message::ReadBoxSettings(info) => {
+                if let Some(celsius) = info.settings.cpu_temp() {
+                    self.on_temperature(celsius);
+                }
+                self.on_phone_details(&info.settings);
+            }
+            _ => {}
+        }
+    }
+
+    fn update_identity(&mut self, message: &Message) {
+        if !self.identity.update(message) {
+            return;
+        }
+        let identity = self.identity.clone();
+        self.dongle.state.lock().unwrap().identity = identity.clone();
+        if !identity.is_complete() {
+            return;
+        }
+        info!("Dongle identified as {:?}", identity);
+        let _ = self.dongle.events.send(DriverEvent::Identified(identity));
+    }
+
+    fn on_phone_details(&self, settings: &BoxSettings) {
+        let Some(mut phone) = self.dongle.state.lock().unwrap().connected_phone.clone() else {
+            return;
+        };
+        if phone.update(settings) {
+            self.set_connected_phone(Some(phone));
+        }
+    }
+
+    fn set_connected_phone(&self, phone: Option<ConnectedPhone>) {
+        match &phone {
+            Some(phone) => info!("Connected phone: {}", phone),
+            None => info!("Phone disconnected"),
+        }
+        self.dongle.state.lock().unwrap().connected_phone = phone.clone();
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::ConnectedPhoneChanged(phone));
+    }
+
+    fn set_hicar_link(&self, link: Option<String>) {
+        {
+            let mut state = self.dongle.state.lock().unwrap();
+            if state.hicar_link == link {
+                return;
+            }
+            state.hicar_link = link.clone();
+        }
+        match &link {
+            Some(link) => info!("HiCar link: {}", link),
+            None => info!("HiCar phone connected, dropping its link"),
+        }
+        let _ = self.dongle.events.send(DriverEvent::HiCarLinkChanged(link));
+    }
+
+    fn on_temperature(&self, celsius: f64) {
+        let Some(level) = self
+            .dongle
+            .state
+            .lock()
+            .unwrap()
+            .temperature
+            .record(celsius)
+        else {
+            return;
+        };
+        match level {
+            TemperatureLevel::Critical => error!("Dongle CPU is at {} °C", celsius),
+            TemperatureLevel::Warning => warn!("Dongle CPU is at {} °C", celsius),
+            TemperatureLevel::Normal => info!("Dongle CPU is back to {} °C", celsius),
+        }
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::TemperatureLevelChanged { level, celsius });
+    }
+
+    fn on_paired_list(&self, devices: &[PairedDevice]) {
+        info!("Paired phones: {:?}", devices);
+        self.dongle.state.lock().unwrap().paired_devices = devices.to_vec();
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::PairedDevicesChanged(devices.to_vec()));
+    }
+
+    fn on_pairing(&self, event: PairingEvent) {
+        info!("Bluetooth: {:?}", event);
+        let _ = self.dongle.events.send(DriverEvent::Pairing(event));
+    }
+
+    async fn on_command(&mut self, command: CommandMapping) {
+        match command {
+            CommandMapping::BtConnected => {
+                self.on_pairing(PairingEvent::Connected);
+                self.dongle.scheduler.cancel(PAIRING_TIMEOUT_TASK);
+                self.dongle.finish_pairing(PairingResult::Paired);
+            }
+            CommandMapping::BtDisconnected => self.on_pairing(PairingEvent::Disconnected),
+            CommandMapping::WifiConnected => {
+                self.dongle.scheduler.cancel(WIFI_RETRY_TASK);
+                let _ = self
+                    .dongle
+                    .events
+                    .send(DriverEvent::Wifi(self.wifi.on_connected()));
+            }
+            command if WifiRetry::is_failure(command) => self.on_wifi_failure(command).await,
+            _ => {}
+        }
+    }
+
+    async fn on_wifi_failure(&mut self, reason: CommandMapping) {
+        // One failed attempt can be reported more than once
+        if self.dongle.scheduler.is_pending(WIFI_RETRY_TASK) {
+            return;
+        }
+        let event = self.wifi.on_failure(reason);
+        match event {
+            WifiEvent::Retrying {
+                attempt, fell_back, ..
+            } => {
+                if fell_back {
+                    warn!("Wi-Fi failed with {:?}, falling back to 2.4 GHz", reason);
+                    let band = SendCommand {
+                        value: self.wifi.band().command(),
+                    };
+                    if self.dongle.send(Box::new(band)).await.is_err() {
+                        error!("WifiBand error: {}", DriverError::SendQueueClosed);
+                        return;
+                    }
+                } else {
+                    warn!(
+                        "Wi-Fi failed with {:?}, retrying (attempt {})",
+                        reason, attempt
+                    );
+                }
+                self.dongle.scheduler.after(
+                    WIFI_RETRY_TASK,
+                    self.wifi.retry_delay(),
+                    self.dongle.message_tx.clone(),
+                    Box::new(SendCommand { value: WifiConnect }),
+                );
+            }
+            WifiEvent::GaveUp { attempts, .. } => {
+                error!("Wi-Fi failed with {:?} after {} attempts", reason, attempts);
+            }
+            WifiEvent::Connected { .. } => {}
+        }
+        let _ = self.dongle.events.send(DriverEvent::Wifi(event));
+    }
+
+    async fn on_plugged(&mut self, plugged: &Plugged) {
+        self.stop_frame_requests();
+        self.set_connected_phone(Some(ConnectedPhone::from(plugged)));
+        let phone_type = plugged.phone_type;
+        if phone_type == PhoneType::HiCar {
+            self.set_hicar_link(None);
+        }
+        let (frame_interval, actions, target, video_params) = {
+            let mut state = self.dongle.state.lock().unwrap();
+            let Some(config) = state.config.as_ref() else {
+                return;
+            };
+            let frame_interval = config
+                .phone_config
+                .get(&phone_type)
+                .and_then(|phone_config| phone_config.frame_interval);
+            let mut target = config.for_phone(phone_type);
+            if phone_type == PhoneType::AndroidAuto {
+                // Only Open carries the frame rate, and it is not resent
+                target.fps = state
+                    .negotiated
+                    .map(|params| params.fps)
+                    .or(state.applied.as_ref().map(|applied| applied.fps))
+                    .unwrap_or(config.fps);
+            }
+            let actions = match &state.applied {
+                Some(applied) => profile_actions(applied, &target, phone_type),
+                None => Vec::new(),
+            };
+            let video_params = match state.negotiated {
+                Some(params)
+                    if phone_type != PhoneType::AndroidAuto
+                        && !actions.contains(&InitAction::Open) =>
+                {
+                    VideoParams::from(params)
+                }
+                _ => VideoParams {
+                    width: target.width,
+                    height: target.height,
+                    fps: target.fps,
+                },
+            };
+            state.applied = Some(target.clone());
+            (frame_interval, actions, target, video_params)
+        };
+
+        info!("{:?} plugged, expecting {:?}", phone_type, video_params);
+        for action in actions {
+            info!("Applying {:?} profile: {:?}", phone_type, action);
+            let messages = match action.messages(&target).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("{:?} error: {}", action, e);
+                    continue;
+                }
+            };
+            for message in messages {
+                if self.dongle.send(message).await.is_err() {
+                    error!("{:?} error: {}", action, DriverError::SendQueueClosed);
+                    return;
+                }
+            }
+        }
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::VideoParamsChanged(video_params));
+        if let Some(frame_interval) = frame_interval {
+            self.start_frame_requests(Duration::from_millis(frame_interval as u64));
+        }
+    }
+
+    /// Periodically asks the phone for a fresh IDR frame so the decoder
+    /// recovers quickly from lost packets.
+    fn start_frame_requests(&self, period: Duration) {
+        self.dongle
+            .scheduler
+            .every(FRAME_TASK, period, self.dongle.message_tx.clone(), || {
+                Box::new(SendCommand { value: Frame })
+            });
+    }
+
+    fn stop_frame_requests(&self) {
+        self.dongle.scheduler.cancel(FRAME_TASK);
+    }
+
+    fn on_opened(&self, params: NegotiatedParams) {
+        let overridden = {
+            let mut state = self.dongle.state.lock().unwrap();
+            state.negotiated = Some(params);
+            // After a profile resent Open, the profile's values are the requested ones
+            match state.applied.as_ref().or(state.config.as_ref()) {
+                Some(config) => params.overridden_fields(config),
+                None => Vec::new(),
+            }
+        };
+        info!("Dongle opened with {:?}", params);
+        for field in &overridden {
+            warn!(
+                "Dongle changed {} from {} to {}",
+                field.name, field.requested, field.negotiated
+            );
+        }
+        // Nobody listening is fine
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::Negotiated { params, overridden });
+        let _ = self
+            .dongle
+            .events
+            .send(DriverEvent::VideoParamsChanged(VideoParams::from(params)));
+    }
+}
+
+/// Returns the number of bulk transfers the message took.
+/// Rounds `transfer_size` down to whole packets of `packet_size` bytes. A
+/// short packet ends the transfer, so the dongle would cut the message there.
+fn packet_aligned(transfer_size: usize, packet_size: usize) -> usize {
+    let packet_size = packet_size.max(1);
+    (transfer_size / packet_size).max(1) * packet_size
+}
+
+fn max_packet_size(interface: &Interface, endpoint: u8) -> Option<usize> {
+    interface.descriptors().find_map(|alt_setting| {
+        alt_setting
+            .endpoints()
+            .find(|e| e.address() == endpoint)
+            .map(|e| e.max_packet_size())
+    })
+}
+
+async fn send_message(
+    out_ep: u8,
+    interface: &Interface,
+    payload: Vec<u8>,
+    transfer_size: Option<usize>,
+    packet_size: usize,
+) -> Result<usize, DriverError> {
+    let transfer_size = match transfer_size {
+        Some(transfer_size) => packet_aligned(transfer_size, packet_size),
+        None => payload.len().max(1),
+    };
+    let mut transfers = 0;
+    for chunk in payload.chunks(transfer_size) {
+        match time::timeout(SEND_TIMEOUT, interface.bulk_out(out_ep, chunk.to_vec())).await {
+            Ok(completion) => completion.into_result()?,
+            Err(_) => return Err(DriverError::Timeout(SEND_TIMEOUT)),
+        };
+        transfers += 1;
+    }
+    Ok(transfers)
+}
+
+async fn read_message(in_ep: u8, interface: &Interface) -> Result<Message, DriverError> {
+    let header_data = interface
+        .bulk_in(in_ep, RequestBuffer::new(HEADER_DATA_LENGTH))
+        .await
+        .into_result()?;
+    let header = MessageHeader::from_bytes(&header_data)
+        .map_err(|e| DriverError::ProtocolViolation(e.to_string()))?;
+    info!("Received message {:?}", header);
+
+    let extra_data = if header.length > 0 {
+        let data = interface
+            .bulk_in(in_ep, RequestBuffer::new(header.length as usize))
+            .await
+            .into_result()?;
+        if data.len() != header.length as usize {
+            return Err(DriverError::ProtocolViolation(format!(
+                "{:?} announced {} bytes, received {}",
+                header.msg_type,
+                header.length,
+                data.len()
+            )));
+        }
+        Some(data)
+    } else {
+        None
+    };
+
+    let message = header
+        .to_message(extra_data)
+        .map_err(|e| DriverError::ProtocolViolation(e.to_string()))?;
+    Ok(*message)
+}
+
 pub async fn send_loop(
     out_ep: u8,
     interface: Interface,
     message_mutex: Arc<tokio::sync::Mutex<Receiver<Box<dyn SendableMessage + Send>>>>,
 ) {
+    let packet_size = max_packet_size(&interface, out_ep).unwrap_or_else(|| {
+        warn!(
+            "No descriptor for endpoint {:#04x}, split messages may be cut short",
+            out_ep
+        );
+        1
+    });
     let mut message_rx = message_mutex.lock().await;
     loop {
         match message_rx.recv().await {
             Some(message) => {
                 info!("Sending message {:?}", message.message_type());
                 let payload = message.serialize();
-
-                match interface.bulk_out(out_ep, payload).await.into_result() {
-                    Ok(a) => {
-                        info!("Message sent {:?}", a);
+                let result = send_message(
+                    out_ep,
+                    &interface,
+                    payload,
+                    message.transfer_size(),
+                    packet_size,
+                )
+                .await;
+                match &result {
+                    Ok(transfers) => {
+                        info!("Message sent in {} transfers", transfers);
                     }
                     Err(e) => {
                         error!("Error sending message: {}", e);
                     }
                 }
+                message.sent(result.as_ref().copied());
             }
             None => {
-                error!("No message received");
+                error!("{}", DriverError::SendQueueClosed);
+                return;
             }
         }
         tokio::time::sleep(Duration::from_secs_f32(0.01)).await;
@@ -370,43 +1240,10 @@ pub async fn send_loop(
 
 pub async fn read_loop(in_ep: u8, interface: Interface, message_tx: Sender<Message>) {
     loop {
-        match interface
-            .bulk_in(in_ep, RequestBuffer::new(HEADER_DATA_LENGTH))
-            .await
-            .into_result()
-        {
-            Ok(header_data) => {
-                let header = match MessageHeader::from_bytes(&header_data) {
-                    Ok(h) => h,
-                    Err(e) => {
-                        error!("Error parsing header: {}", e);
-                        continue;
-                    }
-                };
-                info!("Received message {:?}", header);
-
-                let extra_data = if header.length > 0 {
-                    match interface
-                        .bulk_in(in_ep, RequestBuffer::new(header.length as usize))
-                        .await
-                        .into_result()
-                    {
-                        Ok(data) => Some(data),
-                        Err(e) => {
-                            error!("Failed to read extra data: {}", e);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
-
-                let message = header.to_message(extra_data).unwrap();
-                match message_tx.send(*message) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Error passing on message ({:?}): {}", header.msg_type, e);
-                    }
+        match read_message(in_ep, &interface).await {
+            Ok(message) => {
+                if let Err(e) = message_tx.send(message) {
+                    error!("Error passing on message: {}", e);
                 }
             }
             Err(e) => {
@@ -416,3 +1253,19 @@ pub async fn read_loop(in_ep: u8, interface: Interface, message_tx: Sender<Messa
         tokio::time::sleep(Duration::from_secs_f32(0.01)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_transfers_are_whole_packets() {
+        assert_eq!(packet_aligned(49152, 512), 49152);
+        assert_eq!(packet_aligned(1000, 512), 512);
+        assert_eq!(packet_aligned(1024, 512), 1024);
+        assert_eq!(packet_aligned(36, 512), 512);
+        assert_eq!(packet_aligned(100, 64), 64);
+        assert_eq!(packet_aligned(100, 1), 100);
+        assert_eq!(packet_aligned(100, 0), 100);
+    }
+}
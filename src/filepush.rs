@@ -0,0 +1,79 @@
+use crate::driver::{DongleConfig, DriverError};
+use crate::messagetypes::MessageType;
+use crate::sendable::{FileAddress, SendFile, SendableMessage};
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// Refuses paths other than the known `FileAddress` ones unless `expert`.
+pub fn check_path(path: &str, expert: bool) -> Result<(), DriverError> {
+    if expert || FileAddress::from_path(path).is_some() {
+        Ok(())
+    } else {
+        Err(DriverError::ExpertOnly(path.to_string()))
+    }
+}
+
+/// How a file push went out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePushReport {
+    pub path: String,
+    /// Content bytes, without the message framing
+    pub bytes: usize,
+    /// Bulk transfers the message was split into
+    pub transfers: usize,
+}
+
+/// A `SendFile` sent in transfers of at most `transfer_size` bytes, which
+/// reports back once the send loop is done with it.
+pub struct PushFile {
+    file: SendFile,
+    transfer_size: usize,
+    result: Mutex<Option<oneshot::Sender<Result<FilePushReport, String>>>>,
+}
+
+impl PushFile {
+    pub fn new(
+        file: SendFile,
+        transfer_size: usize,
+    ) -> (Self, oneshot::Receiver<Result<FilePushReport, String>>) {
+        let (tx, rx) = oneshot::channel();
+        let push = Self {
+            file,
+            transfer_size: transfer_size.max(1),
+            result: Mutex::new(Some(tx)),
+        };
+        (push, rx)
+    }
+
+    /// A checked push that nobody waits on, for start-up steps.
+    pub fn queued(file: SendFile, config: &DongleConfig) -> Result<Self, DriverError> {
+        check_path(file.path(), config.expert)?;
+        Ok(Self::new(file, config.packet_max as usize).0)
+    }
+}
+
+impl SendableMessage for PushFile {
+    fn message_type(&self) -> MessageType {
+        self.file.message_type()
+    }
+    fn get_payload(&self) -> Vec<u8> {
+        self.file.get_payload()
+    }
+    fn transfer_size(&self) -> Option<usize> {
+        Some(self.transfer_size)
+    }
+    fn sent(&self, result: Result<usize, &DriverError>) {
+        let Some(tx) = self.result.lock().unwrap().take() else {
+            return;
+        };
+        let _ = tx.send(
+            result
+                .map(|transfers| FilePushReport {
+                    path: self.file.path().to_string(),
+                    bytes: self.file.content().len(),
+                    transfers,
+                })
+                .map_err(|e| e.to_string()),
+        );
+    }
+}
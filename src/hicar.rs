@@ -0,0 +1,37 @@
+use qrcode::types::QrError;
+use qrcode::{Color, QrCode};
+
+/// Light modules around the code that scanners need to find it.
+pub const QUIET_ZONE: usize = 4;
+
+/// The QR code a HiCar phone scans to connect to the dongle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiCarQrCode {
+    pub link: String,
+    width: usize,
+    dark: Vec<bool>,
+}
+
+impl HiCarQrCode {
+    pub fn new(link: &str) -> Result<Self, QrError> {
+        let code = QrCode::new(link.as_bytes())?;
+        Ok(Self {
+            link: link.to_string(),
+            width: code.width(),
+            dark: code
+                .to_colors()
+                .into_iter()
+                .map(|color| color == Color::Dark)
+                .collect(),
+        })
+    }
+
+    /// Modules per side, without the quiet zone.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.width && self.dark[y * self.width + x]
+    }
+}
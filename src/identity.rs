@@ -0,0 +1,292 @@
+use crate::message::Message;
+use crate::messagetypes::{PhoneType, WifiFlag};
+use crate::readable::{BoxInfo, BoxSettings, Plugged};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid MAC address {0:?}")]
+pub struct InvalidMacAddress(pub String);
+
+/// A Bluetooth or Wi-Fi hardware address, shown as `AA:BB:CC:DD:EE:FF`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = InvalidMacAddress;
+
+    /// Accepts hex pairs in either case, separated by either `:` or `-`
+    /// throughout.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidMacAddress(value.to_string());
+        let mut bytes = [0u8; 6];
+        let separator = if value.contains('-') { '-' } else { ':' };
+        let mut parts = value.split(separator);
+        for byte in &mut bytes {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(MacAddress(bytes))
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = InvalidMacAddress;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(address: MacAddress) -> Self {
+        address.to_string()
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// What the dongle reports about itself after start-up, collected from
+/// separate messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DongleIdentity {
+    pub bluetooth_address: Option<MacAddress>,
+    pub bluetooth_name: Option<String>,
+    pub wifi_name: Option<String>,
+    pub firmware_version: Option<String>,
+    /// The two `ManufacturerInfo` values, whose meaning is not known
+    pub manufacturer: Option<(u32, u32)>,
+    pub oem_name: Option<String>,
+    pub hardware_version: Option<String>,
+    pub product_type: Option<String>,
+    pub uuid: Option<String>,
+    pub wifi_channel: Option<u32>,
+    /// Firmware may leave out any of the box settings fields above
+    #[serde(skip)]
+    box_details_received: bool,
+}
+
+impl DongleIdentity {
+    /// Takes what `message` tells about the dongle. Returns whether it
+    /// changed anything.
+    pub fn update(&mut self, message: &Message) -> bool {
+        match message {
+            Message::ReadBluetoothAddress(address) => match address.address.parse::<MacAddress>() {
+                Ok(address) => set(&mut self.bluetooth_address, address),
+                Err(e) => {
+                    warn!("Dongle sent {}", e);
+                    false
+                }
+            },
+            Message::ReadBluetoothDeviceName(name) => {
+                set(&mut self.bluetooth_name, name.name.as_str())
+            }
+            Message::ReadWifiDeviceName(name) => set(&mut self.wifi_name, name.name.as_str()),
+            Message::ReadSoftwareVersion(version) => {
+                set(&mut self.firmware_version, version.version.as_str())
+            }
+            Message::ReadManufacturerInfo(info) => set(&mut self.manufacturer, (info.a, info.b)),
+            Message::ReadBoxSettings(BoxInfo { settings, .. }) => {
+                if !settings.is_dongle_details() {
+                    return false;
+                }
+                let received = !std::mem::replace(&mut self.box_details_received, true);
+                received
+                    | set_some(&mut self.oem_name, settings.oem_name())
+                    | set_some(&mut self.hardware_version, settings.hw_version())
+                    | set_some(&mut self.product_type, settings.product_type())
+                    | set_some(&mut self.uuid, settings.uuid())
+                    | set_some(&mut self.wifi_channel, settings.wifi_channel())
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.bluetooth_address.is_some()
+            && self.bluetooth_name.is_some()
+            && self.wifi_name.is_some()
+            && self.firmware_version.is_some()
+            && self.manufacturer.is_some()
+            && self.box_details_received
+    }
+}
+
+impl fmt::Display for DongleIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn line(f: &mut fmt::Formatter<'_>, name: &str, value: Option<String>) -> fmt::Result {
+            writeln!(
+                f,
+                "{:<18} {}",
+                format!("{}:", name),
+                value.as_deref().unwrap_or("unknown")
+            )
+        }
+        line(f, "Firmware version", self.firmware_version.clone())?;
+        line(
+            f,
+            "Manufacturer info",
+            self.manufacturer.map(|(a, b)| format!("{} {}", a, b)),
+        )?;
+        line(
+            f,
+            "Bluetooth address",
+            self.bluetooth_address.map(|address| address.to_string()),
+        )?;
+        line(f, "Bluetooth name", self.bluetooth_name.clone())?;
+        line(f, "Wi-Fi name", self.wifi_name.clone())?;
+        line(
+            f,
+            "Wi-Fi channel",
+            self.wifi_channel.map(|channel| channel.to_string()),
+        )?;
+        line(f, "OEM name", self.oem_name.clone())?;
+        line(f, "Hardware version", self.hardware_version.clone())?;
+        line(f, "Product type", self.product_type.clone())?;
+        line(f, "UUID", self.uuid.clone())
+    }
+}
+
+/// The phone connected to the dongle, from `Plugged` and the `MD*` keys of
+/// the box settings that follow it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectedPhone {
+    pub phone_type: PhoneType,
+    pub wifi: Option<WifiFlag>,
+    pub link_type: Option<String>,
+    pub model: Option<String>,
+    pub os_version: Option<String>,
+    pub link_version: Option<String>,
+}
+
+impl From<&Plugged> for ConnectedPhone {
+    fn from(plugged: &Plugged) -> Self {
+        Self {
+            phone_type: plugged.phone_type,
+            wifi: plugged.wifi,
+            link_type: None,
+            model: None,
+            os_version: None,
+            link_version: None,
+        }
+    }
+}
+
+impl ConnectedPhone {
+    /// Takes the phone details from `settings`. Returns whether it changed
+    /// anything.
+    pub fn update(&mut self, settings: &BoxSettings) -> bool {
+        set_some(&mut self.link_type, settings.md_link_type())
+            | set_some(&mut self.model, settings.md_model())
+            | set_some(&mut self.os_version, settings.md_os_version())
+            | set_some(&mut self.link_version, settings.md_link_version())
+    }
+}
+
+impl fmt::Display for ConnectedPhone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+        write!(
+            f,
+            "{} {} ({}), {} over {} {}",
+            unknown(&self.model),
+            unknown(&self.os_version),
+            self.phone_type,
+            if self.wifi == Some(WifiFlag::On) {
+                "wireless"
+            } else {
+                "wired"
+            },
+            unknown(&self.link_type),
+            unknown(&self.link_version),
+        )
+    }
+}
+
+fn set_some<T: PartialEq>(field: &mut Option<T>, value: Option<T>) -> bool {
+    match value {
+        Some(value) => set(field, value),
+        None => false,
+    }
+}
+
+fn set<T, V: PartialEq<T> + Into<T>>(field: &mut Option<T>, value: V) -> bool {
+    if field.as_ref().is_some_and(|current| value == *current) {
+        return false;
+    }
+    *field = Some(value.into());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mac_addresses() {
+        let expected = MacAddress([0xAA, 0xBB, 0x0C, 0xDD, 0xEE, 0x0F]);
+        for value in [
+            "AA:BB:0C:DD:EE:0F",
+            "aa:bb:0c:dd:ee:0f",
+            "aa-bb-0c-dd-ee-0f",
+            "Aa:bB:0c:Dd:eE:0F",
+        ] {
+            assert_eq!(value.parse::<MacAddress>(), Ok(expected), "{}", value);
+        }
+        assert_eq!(expected.to_string(), "AA:BB:0C:DD:EE:0F");
+    }
+
+    #[test]
+    fn rejects_malformed_mac_addresses() {
+        for value in [
+            "",
+            "AA:BB:CC:DD:EE",
+            "AA:BB:CC:DD:EE:FF:00",
+            "AA:BB:CC:DD:EE:F",
+            "AA:BB:CC:DD:EE:FFF",
+            "AA:BB:CC:DD:EE:GG",
+            "AA:BB:CC:DD:EE:+F",
+            "AABBCCDDEEFF",
+            "AA:BB:CC:DD:EE:FF ",
+            "AA:BB:CC:DD:EE:",
+            "Aa:bB-0c:Dd-eE:0F",
+            "AA-BB-CC-DD-EE:FF",
+        ] {
+            assert_eq!(
+                value.parse::<MacAddress>(),
+                Err(InvalidMacAddress(value.to_string())),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn mac_addresses_round_trip_through_serde() {
+        let address: MacAddress = serde_json::from_str("\"aa-bb-cc-dd-ee-ff\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&address).unwrap(),
+            "\"AA:BB:CC:DD:EE:FF\""
+        );
+        assert!(serde_json::from_str::<MacAddress>("\"not a mac\"").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::driver::{read_loop, send_loop, DongleConfig, DongleDriver, DriverError, DriverEvent};
+use crate::identity::DongleIdentity;
+use crate::startup::{InitAction, InitStep};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+
+/// Starts the dongle with just enough of the start-up sequence for it to
+/// describe itself, and waits up to `timeout` for all of it.
+pub async fn dongle_info(
+    mut config: DongleConfig,
+    timeout: Duration,
+) -> Result<DongleIdentity, (DriverError, DongleIdentity)> {
+    config.init_sequence = Some(vec![
+        InitStep::new(InitAction::Open),
+        InitStep::new(InitAction::BoxSettings),
+    ]);
+    config.extra_init_steps.clear();
+    config.auto_night_mode = None;
+    // Only looks, never writes
+    config.branding = None;
+
+    let mut dongle = DongleDriver::new();
+    let mut events = dongle.subscribe();
+    let (message_tx, message_rx) = mpsc::channel(64);
+    let (tx, _) = broadcast::channel(64);
+    let handle = dongle.handle(message_tx.clone());
+
+    let result = time::timeout(timeout, async {
+        dongle.initialize().await?;
+        dongle.start(config, message_tx, tx.subscribe()).await?;
+        let interface = dongle.interface.clone().unwrap();
+        tokio::spawn(read_loop(
+            dongle.in_ep.unwrap(),
+            interface.clone(),
+            tx.clone(),
+        ));
+        tokio::spawn(send_loop(
+            dongle.out_ep.unwrap(),
+            interface,
+            Arc::new(tokio::sync::Mutex::new(message_rx)),
+        ));
+        loop {
+            match events.recv().await {
+                Ok(DriverEvent::Identified(identity)) => return Ok(identity),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(DriverError::SendQueueClosed)
+                }
+            }
+        }
+    })
+    .await
+    .unwrap_or(Err(DriverError::Timeout(timeout)));
+
+    let _ = dongle.close().await;
+    result.map_err(|e| (e, handle.partial_identity()))
+}
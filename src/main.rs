@@ -1,10 +1,15 @@
 #![allow(dead_code)]
 
+use crate::bluetooth::{PairingEvent, DEFAULT_PAIRING_TIMEOUT};
+use crate::cli::{Args, Command, OutputFormat};
 use crate::driver::read_loop;
 use crate::driver::send_loop;
 use crate::driver::DongleConfig;
 use crate::driver::DongleDriver;
+use crate::driver::DriverEvent;
+use crate::hicar::{HiCarQrCode, QUIET_ZONE};
 use crate::message::Message;
+use clap::Parser;
 use futures::executor::block_on;
 use gstgtk4::PaintableSink;
 use gstreamer::prelude::ElementExt;
@@ -12,6 +17,8 @@ use gstreamer::prelude::GstBinExtManual;
 use gstreamer::prelude::{Cast, GstObjectExt};
 use gstreamer::ElementFactory;
 use gstreamer::{glib, MessageView};
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
@@ -19,6 +26,7 @@ use std::thread::sleep;
 use std::time::Duration;
 use test_log::env_logger;
 use tokio::sync::broadcast::channel;
+use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::broadcast::Sender;
 
@@ -26,11 +34,14 @@ use crate::readable::ReadableMessage;
 use crate::sendable::SendTouch;
 use crate::sendable::SendableMessage;
 use crate::sendable::TouchAction;
+use crate::temperature::TemperatureLevel;
 use gstreamer::glib::SourceId;
 use gstreamer_audio::AudioInfo;
 use gtk::prelude::ApplicationExt;
 use gtk::prelude::ApplicationExtManual;
 use gtk::prelude::BoxExt;
+use gtk::prelude::DrawingAreaExt;
+use gtk::prelude::DrawingAreaExtManual;
 use gtk::prelude::GestureExt;
 use gtk::prelude::GtkWindowExt;
 use gtk::prelude::WidgetExt;
@@ -40,20 +51,31 @@ use gtk::Orientation;
 use log::error;
 use tokio::sync::mpsc;
 
+mod bluetooth;
+mod branding;
+mod cli;
 mod commands;
+mod config;
 mod driver;
+mod filepush;
+mod hicar;
+mod identity;
+mod info;
 mod message;
 mod messagetypes;
+mod negotiation;
+mod nightmode;
 mod readable;
+mod scheduler;
 mod sendable;
-
-async fn setup_dongle(
-    tx: Sender<Message>,
-    dongle_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
-    dongle_rx: mpsc::Receiver<Box<dyn SendableMessage + Send>>,
-) {
-    let mut dongle = DongleDriver::new();
-    let config = DongleConfig {
+mod startup;
+mod temperature;
+mod timesync;
+mod wifi;
+
+/// What the app runs with, and what a config file is laid over.
+fn default_config() -> DongleConfig {
+    DongleConfig {
         android_work_mode: Some(false),
         box_name: String::from("test"),
         night_mode: true,
@@ -62,22 +84,84 @@ async fn setup_dongle(
         fps: 60,
         media_delay: 200,
         ..Default::default()
-    };
-    block_on(dongle.initialize()).unwrap();
-    block_on(dongle.start(config, dongle_tx)).unwrap();
+    }
+}
+
+async fn setup_dongle(
+    mut dongle: DongleDriver,
+    config: DongleConfig,
+    tx: Sender<Message>,
+    dongle_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+    dongle_rx: mpsc::Receiver<Box<dyn SendableMessage + Send>>,
+    pair: bool,
+) {
+    if let Err(e) = block_on(dongle.initialize()) {
+        error!("Failed to initialize dongle: {}", e);
+        return;
+    }
+    let handle = dongle.handle(dongle_tx.clone());
+    if let Err(e) = block_on(dongle.start(config, dongle_tx, tx.subscribe())) {
+        error!("Failed to start dongle: {}", e);
+        return;
+    }
     let in_ep = dongle.in_ep.unwrap().clone();
     let out_ep = dongle.out_ep.unwrap().clone();
     let interface = dongle.interface.unwrap();
     tokio::spawn(read_loop(in_ep, interface.clone(), tx.clone()));
     let rx_mutex = Arc::new(tokio::sync::Mutex::new(dongle_rx));
     tokio::spawn(send_loop(out_ep, interface.clone(), rx_mutex.clone()));
+    if pair {
+        if let Err(e) = block_on(handle.start_pairing(DEFAULT_PAIRING_TIMEOUT)) {
+            error!("Failed to start pairing: {}", e);
+        }
+    }
+}
+
+/// Prints what the dongle reports about itself. Returns the exit code.
+fn print_info(config: DongleConfig, format: OutputFormat, timeout: Duration) -> i32 {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (identity, code) = match rt.block_on(info::dongle_info(config, timeout)) {
+        Ok(identity) => (identity, 0),
+        Err((e, identity)) => {
+            eprintln!("Incomplete dongle info: {}", e);
+            (identity, 1)
+        }
+    };
+    match format {
+        OutputFormat::Text => print!("{}", identity),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&identity).unwrap()),
+    }
+    code
 }
 
 pub fn main() {
     env_logger::init();
+    let args = Args::parse();
+    let config = match args.dongle_config(default_config()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let validation = config.validate();
+    if !validation.is_valid() {
+        for e in &validation.errors {
+            eprintln!("Invalid config: {}", e);
+        }
+        std::process::exit(2);
+    }
+    if let Some(Command::Info { format, timeout }) = args.command {
+        std::process::exit(print_info(config, format, Duration::from_secs(timeout)));
+    }
+
     gstreamer::init().unwrap();
     gtk::init().unwrap();
     gstgtk4::plugin_register_static().expect("Failed to register gstgtk4 plugin");
+    set_dark_theme(config.night_mode);
 
     let (tx, _) = channel(64);
     let (dongle_tx, dongle_rx) = mpsc::channel(64);
@@ -86,10 +170,19 @@ pub fn main() {
         .build()
         .unwrap();
 
-    let d = rt.spawn(setup_dongle(tx.clone(), dongle_tx.clone(), dongle_rx));
+    let dongle = DongleDriver::new();
+    let events = dongle.subscribe();
+    let d = rt.spawn(setup_dongle(
+        dongle,
+        config.clone(),
+        tx.clone(),
+        dongle_tx.clone(),
+        dongle_rx,
+        args.pair,
+    ));
 
     let a = rt.spawn(audio(tx.clone()));
-    video_streamer_and_gui(tx.clone(), dongle_tx.clone());
+    video_streamer_and_gui(&config, tx.clone(), dongle_tx.clone(), events);
     match block_on(d) {
         Ok(_) => {}
         Err(e) => {
@@ -255,8 +348,10 @@ async fn audio(tx: Sender<Message>) {
 }
 
 fn video_streamer_and_gui(
+    config: &DongleConfig,
     tx: Sender<Message>,
     dongle_tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+    mut events: Receiver<DriverEvent>,
 ) {
     let appsrc = gstreamer_app::AppSrc::builder()
         .name("video_source")
@@ -346,7 +441,55 @@ fn video_streamer_and_gui(
             .build(),
     );
 
+    // The size the dongle actually streams, which it may change from the
+    // config. Touches only take its aspect ratio.
+    let video_size_main = Arc::new(RwLock::new((config.width as f64, config.height as f64)));
+    let video_size = video_size_main.clone();
+    let video_source = appsrc.clone();
+    let pairing_dialog = PairingDialog::new();
+    let dialog = pairing_dialog.clone();
+    let heat_warning = gtk::Label::new(None);
+    heat_warning.set_halign(gtk::Align::End);
+    heat_warning.set_valign(gtk::Align::Start);
+    heat_warning.set_margin_top(12);
+    heat_warning.set_margin_end(12);
+    // Touches go to the video underneath
+    heat_warning.set_can_target(false);
+    heat_warning.set_visible(false);
+    let heat_label = heat_warning.clone();
+    let hicar_code = HiCarCodeView::new();
+    let hicar_view = hicar_code.clone();
+    glib::timeout_add_local(Duration::from_millis(100), move || {
+        loop {
+            match events.try_recv() {
+                Ok(DriverEvent::VideoParamsChanged(params)) => {
+                    *video_size.write().unwrap() = (params.width as f64, params.height as f64);
+                    let caps = gstreamer::Caps::builder("video/x-h264")
+                        .field("stream-format", "byte-stream")
+                        .field("width", params.width as i32)
+                        .field("height", params.height as i32)
+                        .field("framerate", gstreamer::Fraction::new(params.fps as i32, 1))
+                        .build();
+                    video_source.set_caps(Some(&caps));
+                }
+                Ok(DriverEvent::NightModeChanged(night_mode)) => set_dark_theme(night_mode),
+                Ok(DriverEvent::Pairing(event)) => pairing_dialog.update(&event),
+                Ok(DriverEvent::TemperatureLevelChanged { level, celsius }) => {
+                    show_heat_warning(&heat_label, level, celsius)
+                }
+                Ok(DriverEvent::HiCarLinkChanged(link)) => hicar_view.show(link.as_deref()),
+                Ok(_) => {}
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        glib::ControlFlow::Continue
+    });
+
     let video_box = gtk::Box::new(Orientation::Vertical, 0);
+    // Fill the box, so touches map onto the area the picture is scaled into
+    gst_widget.set_hexpand(true);
+    gst_widget.set_vexpand(true);
     video_box.append(&gst_widget);
 
     let drag_controller = gtk::EventControllerMotion::new();
@@ -354,10 +497,11 @@ fn video_streamer_and_gui(
 
     let dongle_tx_clone = dongle_tx.clone();
     let mouse_down = mouse_down_main.clone();
+    let video_size = video_size_main.clone();
+    let widget = video_box.clone();
     drag_controller.connect_motion(move |_controller, x, y| {
         if *mouse_down.read().unwrap() {
-            let x = (x / 1920.0) as f32;
-            let y = (y / 1080.0) as f32;
+            let (x, y) = touch_position(&widget, *video_size.read().unwrap(), x, y);
             let message = SendTouch::new(x, y, TouchAction::Move);
             let dongle_tx_clone = dongle_tx_clone.clone();
             match block_on(dongle_tx_clone.send(Box::new(message))) {
@@ -373,12 +517,13 @@ fn video_streamer_and_gui(
     let click_controller = gtk::GestureClick::new();
     let dongle_tx_clone = dongle_tx.clone();
     let mouse_down = mouse_down_main.clone();
+    let video_size = video_size_main.clone();
+    let widget = video_box.clone();
     click_controller.connect_pressed(move |_gesture, _n_press, x, y| {
         let mut m = mouse_down.write().unwrap();
         *m = true;
         drop(m);
-        let x = (x / 1920.0) as f32;
-        let y = (y / 1080.0) as f32;
+        let (x, y) = touch_position(&widget, *video_size.read().unwrap(), x, y);
         let message = SendTouch::new(x, y, TouchAction::Down);
 
         let dongle_tx_clone = dongle_tx_clone.clone();
@@ -391,12 +536,13 @@ fn video_streamer_and_gui(
     });
     let dongle_tx_clone = dongle_tx.clone();
     let mouse_down = mouse_down_main.clone();
+    let video_size = video_size_main.clone();
+    let widget = video_box.clone();
     click_controller.connect_released(move |_gesture, _n_press, x, y| {
         let mut m = mouse_down.write().unwrap();
         *m = false;
         drop(m);
-        let x = (x / 1920.0) as f32;
-        let y = (y / 1080.0) as f32;
+        let (x, y) = touch_position(&widget, *video_size.read().unwrap(), x, y);
         let dongle_tx_clone = dongle_tx_clone.clone();
         let message = SendTouch::new(x, y, TouchAction::Up);
         match block_on(dongle_tx_clone.send(Box::new(message))) {
@@ -408,6 +554,8 @@ fn video_streamer_and_gui(
     });
     let dongle_tx_clone = dongle_tx.clone();
     let mouse_down = mouse_down_main.clone();
+    let video_size = video_size_main.clone();
+    let widget = video_box.clone();
     click_controller.connect_end(move |_gesture, _seq| {
         let (x, y) = match _gesture.point(_seq) {
             None => return,
@@ -416,8 +564,7 @@ fn video_streamer_and_gui(
         let mut m = mouse_down.write().unwrap();
         *m = false;
         drop(m);
-        let x = (x / 1920.0) as f32;
-        let y = (y / 1080.0) as f32;
+        let (x, y) = touch_position(&widget, *video_size.read().unwrap(), x, y);
         let dongle_tx_clone = dongle_tx_clone.clone();
         let message = SendTouch::new(x, y, TouchAction::Up);
         match block_on(dongle_tx_clone.send(Box::new(message))) {
@@ -459,10 +606,180 @@ fn video_streamer_and_gui(
             .fullscreened(true)
             .build();
 
-        window.set_child(Some(&video_box));
+        let overlay = gtk::Overlay::new();
+        overlay.set_child(Some(&video_box));
+        overlay.add_overlay(&hicar_code.area);
+        overlay.add_overlay(&heat_warning);
+        window.set_child(Some(&overlay));
+        dialog.window.set_transient_for(Some(&window));
         window.show();
     });
-    app.run();
+    // Command-line options are ours, not GTK's
+    app.run_with_args::<&str>(&[]);
+}
+
+/// Shows the Bluetooth PIN while a phone pairs with the dongle.
+#[derive(Clone)]
+struct PairingDialog {
+    window: gtk::Window,
+    instructions: gtk::Label,
+    pin: gtk::Label,
+}
+
+impl PairingDialog {
+    fn new() -> Self {
+        let instructions = gtk::Label::new(Some("Pair your phone with the dongle"));
+        let pin = gtk::Label::new(None);
+        pin.add_css_class("title-1");
+
+        let content = gtk::Box::new(Orientation::Vertical, 12);
+        content.set_margin_top(24);
+        content.set_margin_bottom(24);
+        content.set_margin_start(24);
+        content.set_margin_end(24);
+        content.append(&instructions);
+        content.append(&gtk::Label::new(Some("and check that it shows this PIN")));
+        content.append(&pin);
+
+        let window = gtk::Window::builder()
+            .title("Bluetooth pairing")
+            .modal(true)
+            .hide_on_close(true)
+            .child(&content)
+            .build();
+
+        PairingDialog {
+            window,
+            instructions,
+            pin,
+        }
+    }
+
+    fn update(&self, event: &PairingEvent) {
+        match event {
+            PairingEvent::DongleName(name) if !name.is_empty() => self
+                .instructions
+                .set_text(&format!("Select {} in your phone's Bluetooth settings", name)),
+            PairingEvent::Pin(pin) => {
+                self.pin.set_text(pin);
+                self.window.present();
+            }
+            PairingEvent::Connected | PairingEvent::Finished(_) => self.window.set_visible(false),
+            _ => {}
+        }
+    }
+}
+
+/// Shows the QR code HiCar phones scan to connect, over the video.
+#[derive(Clone)]
+struct HiCarCodeView {
+    area: gtk::DrawingArea,
+    code: Rc<RefCell<Option<HiCarQrCode>>>,
+}
+
+impl HiCarCodeView {
+    const SIZE: i32 = 320;
+
+    fn new() -> Self {
+        let code: Rc<RefCell<Option<HiCarQrCode>>> = Rc::default();
+        let area = gtk::DrawingArea::new();
+        area.set_content_width(Self::SIZE);
+        area.set_content_height(Self::SIZE);
+        area.set_halign(gtk::Align::Center);
+        area.set_valign(gtk::Align::Center);
+        area.set_visible(false);
+        let drawn = code.clone();
+        area.set_draw_func(move |_area, cr, width, height| {
+            if let Some(code) = drawn.borrow().as_ref() {
+                if let Err(e) = draw_qr_code(cr, code, width.min(height) as f64) {
+                    error!("Failed to draw the HiCar QR code: {}", e);
+                }
+            }
+        });
+        HiCarCodeView { area, code }
+    }
+
+    fn show(&self, link: Option<&str>) {
+        let code = match link.map(HiCarQrCode::new) {
+            Some(Ok(code)) => Some(code),
+            Some(Err(e)) => {
+                error!("HiCar link does not fit a QR code: {}", e);
+                None
+            }
+            None => None,
+        };
+        self.area.set_visible(code.is_some());
+        *self.code.borrow_mut() = code;
+        self.area.queue_draw();
+    }
+}
+
+fn draw_qr_code(
+    cr: &gtk::cairo::Context,
+    code: &HiCarQrCode,
+    size: f64,
+) -> Result<(), gtk::cairo::Error> {
+    let scale = size / (code.width() + 2 * QUIET_ZONE) as f64;
+    cr.set_source_rgb(1.0, 1.0, 1.0);
+    cr.rectangle(0.0, 0.0, size, size);
+    cr.fill()?;
+    cr.set_source_rgb(0.0, 0.0, 0.0);
+    for y in 0..code.width() {
+        for x in 0..code.width() {
+            if code.is_dark(x, y) {
+                cr.rectangle(
+                    (x + QUIET_ZONE) as f64 * scale,
+                    (y + QUIET_ZONE) as f64 * scale,
+                    scale,
+                    scale,
+                );
+            }
+        }
+    }
+    cr.fill()
+}
+
+fn show_heat_warning(label: &gtk::Label, level: TemperatureLevel, celsius: f64) {
+    label.remove_css_class("warning");
+    label.remove_css_class("error");
+    match level {
+        TemperatureLevel::Normal => label.set_visible(false),
+        TemperatureLevel::Warning => {
+            label.set_text(&format!("Dongle running hot: {:.0} °C", celsius));
+            label.add_css_class("warning");
+            label.set_visible(true);
+        }
+        TemperatureLevel::Critical => {
+            label.set_text(&format!(
+                "Dongle overheating: {:.0} °C, the connection may drop",
+                celsius
+            ));
+            label.add_css_class("error");
+            label.set_visible(true);
+        }
+    }
+}
+
+/// Maps a point on `widget` to the 0..1 position on the video, which is
+/// scaled to fit the widget and centred between black bars.
+fn touch_position(widget: &gtk::Box, video_size: (f64, f64), x: f64, y: f64) -> (f32, f32) {
+    let (area_width, area_height) = (widget.width() as f64, widget.height() as f64);
+    let (video_width, video_height) = video_size;
+    let scale = (area_width / video_width).min(area_height / video_height);
+    if scale.is_nan() || scale <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let (shown_width, shown_height) = (video_width * scale, video_height * scale);
+    let x = (x - (area_width - shown_width) / 2.0) / shown_width;
+    let y = (y - (area_height - shown_height) / 2.0) / shown_height;
+    (x.clamp(0.0, 1.0) as f32, y.clamp(0.0, 1.0) as f32)
+}
+
+/// Keeps the window in line with the dongle's night mode.
+fn set_dark_theme(dark: bool) {
+    if let Some(settings) = gtk::Settings::default() {
+        settings.set_gtk_application_prefer_dark_theme(dark);
+    }
 }
 
 #[derive(Debug)]
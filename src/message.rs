@@ -6,7 +6,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use log::warn;
 use std::fmt;
 
-const HEADER_SIZE: usize = 16;
+pub const HEADER_SIZE: usize = 16;
 const MAGIC: u32 = 0x55AA55AA;
 
 #[derive(Debug, Clone)]
@@ -52,7 +52,15 @@ pub struct MessageHeader {
 pub enum HeaderBuildError {
     InvalidSize(usize),
     InvalidMagic(u32),
-    InvalidTypeCheck { expected: u32, actual: u32 },
+    InvalidTypeCheck {
+        expected: u32,
+        actual: u32,
+    },
+    PayloadTooShort {
+        msg_type: messagetypes::MessageType,
+        minimum: usize,
+        actual: usize,
+    },
 }
 
 impl fmt::Display for HeaderBuildError {
@@ -65,10 +73,32 @@ impl fmt::Display for HeaderBuildError {
                 "Invalid type check: expected {:08X}, got {:08X}",
                 expected, actual
             ),
+            HeaderBuildError::PayloadTooShort {
+                msg_type,
+                minimum,
+                actual,
+            } => write!(
+                f,
+                "{:?} payload is {} bytes, needs at least {}",
+                msg_type, actual, minimum
+            ),
         }
     }
 }
 
+/// Bytes the parser of each fixed layout payload reads unconditionally.
+fn min_payload_length(msg_type: messagetypes::MessageType) -> usize {
+    use messagetypes::MessageType::*;
+    match msg_type {
+        Command | Plugged | Phase | MediaData => 4,
+        ManufacturerInfo => 8,
+        AudioData => 12,
+        VideoData => VIDEO_HEADER_SIZE,
+        Open => 28,
+        _ => 0,
+    }
+}
+
 impl MessageHeader {
     pub fn from_bytes(data: &[u8]) -> Result<Self, HeaderBuildError> {
         if data.len() != HEADER_SIZE {
@@ -113,6 +143,15 @@ impl MessageHeader {
         use crate::readable::*;
         use crate::sendable::*;
 
+        let minimum = min_payload_length(self.msg_type);
+        if let Some(d) = data.as_ref().filter(|d| d.len() < minimum) {
+            return Err(HeaderBuildError::PayloadTooShort {
+                msg_type: self.msg_type,
+                minimum,
+                actual: d.len(),
+            });
+        }
+
         match (self.msg_type, data) {
             (messagetypes::MessageType::Command, Some(d)) => Ok(Box::new(Message::ReadCommand(
                 Command::new(self.clone(), d),
@@ -207,3 +246,57 @@ impl MessageHeader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messagetypes::MessageType;
+
+    fn header(msg_type: MessageType, length: usize) -> MessageHeader {
+        MessageHeader {
+            length: length as u32,
+            msg_type,
+        }
+    }
+
+    #[test]
+    fn short_payloads_are_rejected() {
+        for (msg_type, minimum) in [
+            (MessageType::Command, 4),
+            (MessageType::ManufacturerInfo, 8),
+            (MessageType::Plugged, 4),
+            (MessageType::Phase, 4),
+            (MessageType::AudioData, 12),
+            (MessageType::VideoData, VIDEO_HEADER_SIZE),
+            (MessageType::MediaData, 4),
+            (MessageType::Open, 28),
+        ] {
+            for length in 0..minimum {
+                let result = header(msg_type, length).to_message(Some(vec![0; length]));
+                assert!(
+                    matches!(
+                        result,
+                        Err(HeaderBuildError::PayloadTooShort { actual, .. }) if actual == length
+                    ),
+                    "{:?} accepted {} bytes",
+                    msg_type,
+                    length
+                );
+            }
+            assert!(header(msg_type, minimum)
+                .to_message(Some(vec![0; minimum]))
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn variable_payloads_may_be_empty() {
+        for msg_type in [
+            MessageType::SoftwareVersion,
+            MessageType::BluetoothPairedList,
+            MessageType::BoxSettings,
+        ] {
+            assert!(header(msg_type, 0).to_message(Some(Vec::new())).is_ok());
+        }
+    }
+}
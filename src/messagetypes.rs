@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageType {
@@ -93,3 +97,137 @@ impl From<MessageType> for u32 {
         }
     }
 }
+
+/// Raw form of a protocol enum in config files: either its name or the
+/// number the protocol uses.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ProtocolValue {
+    Number(u32),
+    Name(String),
+}
+
+/// Declares a `u32` protocol enum whose known values have names for logs and
+/// config files, and whose other values are kept as `Unknown`.
+macro_rules! protocol_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident ($what:literal) {
+            $($(#[$variant_meta:meta])* $variant:ident = $value:literal => $text:literal,)+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        #[serde(try_from = "ProtocolValue", into = "String")]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)+
+            Unknown(u32),
+        }
+
+        impl From<u32> for $name {
+            fn from(value: u32) -> Self {
+                match value {
+                    $($value => $name::$variant,)+
+                    other => $name::Unknown(other),
+                }
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(value: $name) -> u32 {
+                match value {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(code) => code,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    $($name::$variant => write!(f, $text),)+
+                    $name::Unknown(code) => write!(f, "{}", code),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            /// Accepts the names `Display` produces, or the raw protocol value.
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                match value {
+                    $($text => Ok($name::$variant),)+
+                    other => other.parse::<u32>().map($name::from).map_err(|_| {
+                        format!(
+                            "unknown {} `{}`, expected {} or a number",
+                            $what,
+                            other,
+                            [$($text),+].join(", ")
+                        )
+                    }),
+                }
+            }
+        }
+
+        impl TryFrom<ProtocolValue> for $name {
+            type Error = String;
+
+            fn try_from(value: ProtocolValue) -> Result<Self, Self::Error> {
+                match value {
+                    ProtocolValue::Number(number) => Ok($name::from(number)),
+                    ProtocolValue::Name(name) => name.parse(),
+                }
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> String {
+                value.to_string()
+            }
+        }
+    };
+}
+
+protocol_enum! {
+    /// The kind of phone session the dongle reports in `Plugged`. Also keys
+    /// the per-phone-type settings in `DongleConfig`.
+    pub enum PhoneType ("phone type") {
+        AndroidMirror = 1 => "androidmirror",
+        CarPlay = 3 => "carplay",
+        IphoneMirror = 4 => "iphonemirror",
+        AndroidAuto = 5 => "androidauto",
+        HiCar = 6 => "hicar",
+    }
+}
+
+protocol_enum! {
+    /// Video encoding requested in `SendOpen` and confirmed in `Opened`.
+    pub enum VideoFormat ("video format") {
+        /// Annex B H.264, the only format the dongles are known to stream
+        H264 = 5 => "h264",
+    }
+}
+
+protocol_enum! {
+    /// Host protocol version announced in `SendOpen`.
+    pub enum BoxVersion ("box version") {
+        V2 = 2 => "v2",
+    }
+}
+
+protocol_enum! {
+    /// Phone work mode in `SendOpen` and `Opened`. Only the mode every known
+    /// client sends has a name.
+    pub enum PhoneWorkMode ("phone work mode") {
+        Standard = 2 => "standard",
+    }
+}
+
+protocol_enum! {
+    /// Optional second field of `Plugged`, present on dongles with Wi-Fi.
+    pub enum WifiFlag ("wifi flag") {
+        Off = 0 => "off",
+        On = 1 => "on",
+    }
+}
@@ -0,0 +1,83 @@
+use crate::driver::DongleConfig;
+use crate::messagetypes::{BoxVersion, PhoneWorkMode, VideoFormat};
+use crate::readable::Opened;
+
+/// The session parameters the dongle confirmed in its `Opened` reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedParams {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub format: VideoFormat,
+    pub packet_max: u32,
+    pub i_box_version: BoxVersion,
+    pub phone_work_mode: PhoneWorkMode,
+}
+
+impl From<&Opened> for NegotiatedParams {
+    fn from(opened: &Opened) -> Self {
+        Self {
+            width: opened.width,
+            height: opened.height,
+            fps: opened.fps,
+            format: opened.format,
+            packet_max: opened.packet_max,
+            i_box_version: opened.i_box,
+            phone_work_mode: opened.phone_mode,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverriddenField {
+    pub name: &'static str,
+    pub requested: String,
+    pub negotiated: String,
+}
+
+impl NegotiatedParams {
+    /// Lists the `SendOpen` fields the dongle did not accept as requested.
+    pub fn overridden_fields(&self, config: &DongleConfig) -> Vec<OverriddenField> {
+        let mut overridden = Vec::new();
+        let mut compare = |name, requested: &dyn ToString, negotiated: &dyn ToString| {
+            let requested = requested.to_string();
+            let negotiated = negotiated.to_string();
+            if requested != negotiated {
+                overridden.push(OverriddenField {
+                    name,
+                    requested,
+                    negotiated,
+                });
+            }
+        };
+        compare("width", &config.width, &self.width);
+        compare("height", &config.height, &self.height);
+        compare("fps", &config.fps, &self.fps);
+        compare("format", &config.format, &self.format);
+        compare("packet_max", &config.packet_max, &self.packet_max);
+        compare("i_box_version", &config.i_box_version, &self.i_box_version);
+        compare(
+            "phone_work_mode",
+            &config.phone_work_mode,
+            &self.phone_work_mode,
+        );
+        overridden
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoParams {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl From<NegotiatedParams> for VideoParams {
+    fn from(params: NegotiatedParams) -> Self {
+        Self {
+            width: params.width,
+            height: params.height,
+            fps: params.fps,
+        }
+    }
+}
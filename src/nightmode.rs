@@ -0,0 +1,286 @@
+use crate::driver::DongleHandle;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+const SUN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const IIO_DEVICES: &str = "bus/iio/devices";
+const ILLUMINANCE_FILE: &str = "in_illuminance_raw";
+
+/// Where automatic night mode takes its day/night decision from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AutoNightMode {
+    /// Night between local sunset and sunrise, from the system clock
+    Sun { latitude: f64, longitude: f64 },
+    /// Night while an IIO ambient light sensor reads dark. Readings between
+    /// the two thresholds keep the current mode.
+    LightSensor {
+        #[serde(default = "default_sysfs_root")]
+        sysfs_root: PathBuf,
+        /// IIO device name such as `iio:device0`, the first one with an
+        /// illuminance channel when unset
+        #[serde(default)]
+        device: Option<String>,
+        /// Raw reading below which night mode switches on
+        dark_below: u32,
+        /// Raw reading above which night mode switches off
+        light_above: u32,
+        /// Milliseconds between readings
+        #[serde(default = "default_poll_interval")]
+        poll_interval: u32,
+    },
+}
+
+fn default_sysfs_root() -> PathBuf {
+    PathBuf::from("/sys")
+}
+
+fn default_poll_interval() -> u32 {
+    1000
+}
+
+impl AutoNightMode {
+    /// Whether it should be night mode now, given the current mode, if the
+    /// source can tell.
+    pub fn is_night(&self, night_mode: bool) -> Option<bool> {
+        match self {
+            AutoNightMode::Sun {
+                latitude,
+                longitude,
+            } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+                Some(sun_is_down(*latitude, *longitude, now.as_secs_f64()))
+            }
+            AutoNightMode::LightSensor {
+                sysfs_root,
+                device,
+                dark_below,
+                light_above,
+                ..
+            } => match read_illuminance(sysfs_root, device.as_deref()) {
+                Ok(illuminance) if illuminance < *dark_below => Some(true),
+                Ok(illuminance) if illuminance > *light_above => Some(false),
+                Ok(_) => Some(night_mode),
+                Err(e) => {
+                    warn!("Failed to read ambient light sensor: {}", e);
+                    None
+                }
+            },
+        }
+    }
+
+    fn poll_interval(&self) -> Duration {
+        match self {
+            AutoNightMode::Sun { .. } => SUN_CHECK_INTERVAL,
+            AutoNightMode::LightSensor { poll_interval, .. } => {
+                Duration::from_millis(*poll_interval as u64)
+            }
+        }
+    }
+}
+
+/// Keeps the dongle's night mode in line with `source` until aborted.
+pub async fn run_auto_night_mode(source: AutoNightMode, handle: DongleHandle) {
+    let mut interval = time::interval(source.poll_interval());
+    loop {
+        interval.tick().await;
+        let night_mode = handle.night_mode();
+        let Some(night) = source.is_night(night_mode) else {
+            continue;
+        };
+        if night != night_mode {
+            info!("Automatic night mode: switching to {}", night);
+            if let Err(e) = handle.set_night_mode(night).await {
+                error!("Automatic night mode error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Reads `in_illuminance_raw` of `device`, or of the first IIO device that
+/// has one, under `sysfs_root`.
+pub fn read_illuminance(sysfs_root: &Path, device: Option<&str>) -> io::Result<u32> {
+    let devices = sysfs_root.join(IIO_DEVICES);
+    let path = match device {
+        Some(device) => devices.join(device).join(ILLUMINANCE_FILE),
+        None => {
+            let mut candidates: Vec<PathBuf> = fs::read_dir(&devices)?
+                .filter_map(|entry| Some(entry.ok()?.path().join(ILLUMINANCE_FILE)))
+                .filter(|path| path.is_file())
+                .collect();
+            candidates.sort();
+            candidates.into_iter().next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no {} under {}", ILLUMINANCE_FILE, devices.display()),
+                )
+            })?
+        }
+    };
+    fs::read_to_string(&path)?.trim().parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Sunrise equation, good to about a minute. Longitude is positive east,
+/// `unix_time` in seconds.
+pub fn sun_is_down(latitude: f64, longitude: f64, unix_time: f64) -> bool {
+    let julian_date = unix_time / 86400.0 + 2440587.5;
+    let day = (julian_date - 2451545.0 + 0.0008).round();
+    // Check the solar days around now, so the answer does not depend on
+    // which side of UTC midnight the observer's day falls.
+    ![day - 1.0, day, day + 1.0]
+        .iter()
+        .any(|&day| match daylight(latitude, longitude, day) {
+            Daylight::Always => true,
+            Daylight::Never => false,
+            Daylight::Between(rise, set) => (rise..set).contains(&julian_date),
+        })
+}
+
+enum Daylight {
+    Always,
+    Never,
+    Between(f64, f64),
+}
+
+fn daylight(latitude: f64, longitude: f64, day: f64) -> Daylight {
+    let radians = PI / 180.0;
+    let mean_solar_time = day - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_time).rem_euclid(360.0);
+    let center = 1.9148 * (anomaly * radians).sin()
+        + 0.0200 * (2.0 * anomaly * radians).sin()
+        + 0.0003 * (3.0 * anomaly * radians).sin();
+    let ecliptic_longitude = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit = 2451545.0 + mean_solar_time + 0.0053 * (anomaly * radians).sin()
+        - 0.0069 * (2.0 * ecliptic_longitude * radians).sin();
+    let declination = ((ecliptic_longitude * radians).sin() * (23.4397 * radians).sin()).asin();
+    let hour_angle = ((-0.833 * radians).sin() - (latitude * radians).sin() * declination.sin())
+        / ((latitude * radians).cos() * declination.cos());
+
+    if hour_angle < -1.0 {
+        Daylight::Always
+    } else if hour_angle > 1.0 {
+        Daylight::Never
+    } else {
+        let half_day = hour_angle.acos() / radians / 360.0;
+        Daylight::Between(transit - half_day, transit + half_day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty `bus/iio/devices` under a fresh root for each test.
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("rust-carplay-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join(IIO_DEVICES)).unwrap();
+        root
+    }
+
+    fn add_device(root: &Path, device: &str, illuminance: Option<&str>) {
+        let dir = root.join(IIO_DEVICES).join(device);
+        fs::create_dir_all(&dir).unwrap();
+        if let Some(illuminance) = illuminance {
+            fs::write(dir.join(ILLUMINANCE_FILE), illuminance).unwrap();
+        }
+    }
+
+    fn light_sensor(root: &Path) -> AutoNightMode {
+        AutoNightMode::LightSensor {
+            sysfs_root: root.to_path_buf(),
+            device: None,
+            dark_below: 50,
+            light_above: 150,
+            poll_interval: 1000,
+        }
+    }
+
+    #[test]
+    fn finds_the_first_device_with_an_illuminance_channel() {
+        let root = fake_sysfs("first-device");
+        add_device(&root, "iio:device0", None);
+        add_device(&root, "iio:device2", Some("7\n"));
+        add_device(&root, "iio:device1", Some("42\n"));
+        assert_eq!(read_illuminance(&root, None).unwrap(), 42);
+    }
+
+    #[test]
+    fn reads_an_explicit_device() {
+        let root = fake_sysfs("explicit-device");
+        add_device(&root, "iio:device0", Some("42"));
+        add_device(&root, "iio:device1", Some("7"));
+        assert_eq!(read_illuminance(&root, Some("iio:device1")).unwrap(), 7);
+        assert!(read_illuminance(&root, Some("iio:device5")).is_err());
+    }
+
+    #[test]
+    fn keeps_the_current_mode_between_thresholds() {
+        let root = fake_sysfs("hysteresis");
+        let source = light_sensor(&root);
+        for (reading, night_mode, expected) in [
+            ("10", false, true),
+            ("49", false, true),
+            ("50", false, false),
+            ("100", true, true),
+            ("100", false, false),
+            ("150", true, true),
+            ("151", true, false),
+        ] {
+            add_device(&root, "iio:device0", Some(reading));
+            assert_eq!(
+                source.is_night(night_mode),
+                Some(expected),
+                "{} lux in night mode {}",
+                reading,
+                night_mode
+            );
+        }
+    }
+
+    #[test]
+    fn bad_readings_leave_the_decision_open() {
+        let root = fake_sysfs("bad-readings");
+        let source = light_sensor(&root);
+        assert!(read_illuminance(&root, None).is_err());
+        assert_eq!(source.is_night(true), None);
+
+        add_device(&root, "iio:device0", Some("dark"));
+        let e = read_illuminance(&root, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(source.is_night(false), None);
+
+        let missing = light_sensor(&root.join("missing"));
+        assert_eq!(missing.is_night(false), None);
+    }
+
+    #[test]
+    fn sun_is_down_at_known_times() {
+        // Helsinki, 60.17 N 24.94 E, UTC+3 in summer and UTC+2 in winter
+        let (latitude, longitude) = (60.17, 24.94);
+        // 2024-06-21 12:00 UTC, midsummer afternoon
+        assert!(!sun_is_down(latitude, longitude, 1718971200.0));
+        // 2024-06-21 00:00 UTC, 03:00 local before the 03:54 sunrise
+        assert!(sun_is_down(latitude, longitude, 1718928000.0));
+        // 2024-12-21 10:00 UTC, midday in midwinter
+        assert!(!sun_is_down(latitude, longitude, 1734775200.0));
+        // 2024-12-21 22:00 UTC, midnight
+        assert!(sun_is_down(latitude, longitude, 1734818400.0));
+        // Tromsø at midnight on midsummer and at noon on midwinter
+        assert!(!sun_is_down(69.65, 18.96, 1718928000.0));
+        assert!(sun_is_down(69.65, 18.96, 1734778800.0));
+    }
+}
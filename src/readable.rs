@@ -1,11 +1,15 @@
 use crate::commands::CommandMapping;
+use crate::identity::MacAddress;
 use crate::message::MessageHeader;
+use crate::messagetypes::{BoxVersion, PhoneType, PhoneWorkMode, VideoFormat, WifiFlag};
 use byteorder::{LittleEndian, ReadBytesExt};
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::Cursor;
 
+pub const VIDEO_HEADER_SIZE: usize = 20;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum AudioCommand {
@@ -24,6 +28,14 @@ pub enum AudioCommand {
     AudioAlertStop = 13,
 }
 
+/// Payload text without the NUL padding the dongle adds.
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
 pub trait ReadableMessage {
     fn get_data(&self) -> Vec<u8> {
         Vec::new()
@@ -73,7 +85,7 @@ pub struct SoftwareVersion {
 impl ReadableMessage for SoftwareVersion {}
 impl SoftwareVersion {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let version = String::from_utf8_lossy(&*data).into_owned();
+        let version = text(&data);
         SoftwareVersion { header, version }
     }
 }
@@ -87,7 +99,7 @@ pub struct BluetoothAddress {
 impl ReadableMessage for BluetoothAddress {}
 impl BluetoothAddress {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let address = String::from_utf8_lossy(&*data).into_owned();
+        let address = text(&data);
         BluetoothAddress { header, address }
     }
 }
@@ -101,7 +113,7 @@ pub struct BluetoothPIN {
 impl ReadableMessage for BluetoothPIN {}
 impl BluetoothPIN {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let pin = String::from_utf8_lossy(&*data).into_owned();
+        let pin = text(&data);
         BluetoothPIN { header, pin }
     }
 }
@@ -115,7 +127,7 @@ pub struct BluetoothDeviceName {
 impl ReadableMessage for BluetoothDeviceName {}
 impl BluetoothDeviceName {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let name = String::from_utf8_lossy(&*data).into_owned();
+        let name = text(&data);
         BluetoothDeviceName { header, name }
     }
 }
@@ -129,7 +141,7 @@ pub struct WifiDeviceName {
 impl ReadableMessage for WifiDeviceName {}
 impl WifiDeviceName {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let name = String::from_utf8_lossy(&*data).into_owned();
+        let name = text(&data);
         WifiDeviceName { header, name }
     }
 }
@@ -143,47 +155,55 @@ pub struct HiCarLink {
 impl ReadableMessage for HiCarLink {}
 impl HiCarLink {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let link = String::from_utf8_lossy(&*data).into_owned();
+        let link = text(&data);
         HiCarLink { header, link }
     }
 }
 
+const BLUETOOTH_ADDRESS_LENGTH: usize = 17;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedDevice {
+    pub address: MacAddress,
+    pub name: String,
+}
+
+impl PairedDevice {
+    /// An entry is the `AA:BB:CC:DD:EE:FF` address directly followed by the name.
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim();
+        if !entry.is_char_boundary(BLUETOOTH_ADDRESS_LENGTH) {
+            return None;
+        }
+        let (address, name) = entry.split_at(BLUETOOTH_ADDRESS_LENGTH);
+        Some(PairedDevice {
+            address: address.parse().ok()?,
+            name: name.trim().to_string(),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BluetoothPairedList {
     pub header: MessageHeader,
-    pub data: String,
+    pub devices: Vec<PairedDevice>,
 }
 
 impl ReadableMessage for BluetoothPairedList {}
 impl BluetoothPairedList {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let data = String::from_utf8_lossy(&*data).into_owned();
-        BluetoothPairedList { header, data }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum PhoneType {
-    AndroidMirror = 1,
-    CarPlay = 3,
-    IphoneMirror = 4,
-    AndroidAuto = 5,
-    HiCar = 6,
-    Unknown = 255,
-}
-
-impl From<u32> for PhoneType {
-    fn from(value: u32) -> Self {
-        use PhoneType::*;
-        match value {
-            1 => AndroidMirror,
-            3 => CarPlay,
-            4 => IphoneMirror,
-            5 => AndroidAuto,
-            6 => HiCar,
-            _ => Unknown,
+        let data = String::from_utf8_lossy(&data);
+        let mut devices = Vec::new();
+        for entry in data
+            .split(['\n', '\r', '\0'])
+            .filter(|entry| !entry.trim().is_empty())
+        {
+            match PairedDevice::parse(entry) {
+                Some(device) => devices.push(device),
+                None => warn!("Skipping unreadable paired device {:?}", entry),
+            }
         }
+        BluetoothPairedList { header, devices }
     }
 }
 
@@ -191,7 +211,7 @@ impl From<u32> for PhoneType {
 pub struct Plugged {
     pub header: MessageHeader,
     pub phone_type: PhoneType,
-    pub wifi: Option<u32>,
+    pub wifi: Option<WifiFlag>,
 }
 
 impl ReadableMessage for Plugged {}
@@ -201,7 +221,7 @@ impl Plugged {
         let mut cursor = Cursor::new(data);
         let phone_type = PhoneType::from(cursor.read_u32::<LittleEndian>().unwrap());
         let wifi = if data_len == 8 {
-            Some(cursor.read_u32::<LittleEndian>().unwrap())
+            Some(WifiFlag::from(cursor.read_u32::<LittleEndian>().unwrap()))
         } else {
             None
         };
@@ -355,13 +375,13 @@ impl ReadableMessage for VideoData {
 impl VideoData {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
         // TODO: 20 or 21?
-        let mut cursor = Cursor::new(data[..20].to_vec());
+        let mut cursor = Cursor::new(data[..VIDEO_HEADER_SIZE].to_vec());
         let width = cursor.read_u32::<LittleEndian>().unwrap();
         let height = cursor.read_u32::<LittleEndian>().unwrap();
         let flags = cursor.read_u32::<LittleEndian>().unwrap();
         let length = cursor.read_u32::<LittleEndian>().unwrap();
         let unknown = cursor.read_u32::<LittleEndian>().unwrap();
-        let data = data[20..].to_vec();
+        let data = data[VIDEO_HEADER_SIZE..].to_vec();
 
         VideoData {
             header,
@@ -421,7 +441,8 @@ impl MediaData {
 
         let payload = match type_val {
             1 => {
-                let media_data = &data[4..data_len - 1];
+                // The JSON ends in a NUL
+                let media_data = data.get(4..data_len - 1).unwrap_or_default();
                 if let Ok(media) = serde_json::from_slice::<MediaInfo>(media_data) {
                     Some(MediaPayload::Data { media })
                 } else {
@@ -450,10 +471,10 @@ pub struct Opened {
     pub width: u32,
     pub height: u32,
     pub fps: u32,
-    pub format: u32,
+    pub format: VideoFormat,
     pub packet_max: u32,
-    pub i_box: u32,
-    pub phone_mode: u32,
+    pub i_box: BoxVersion,
+    pub phone_mode: PhoneWorkMode,
 }
 
 impl ReadableMessage for Opened {}
@@ -463,10 +484,10 @@ impl Opened {
         let width = cursor.read_u32::<LittleEndian>().unwrap();
         let height = cursor.read_u32::<LittleEndian>().unwrap();
         let fps = cursor.read_u32::<LittleEndian>().unwrap();
-        let format = cursor.read_u32::<LittleEndian>().unwrap();
+        let format = VideoFormat::from(cursor.read_u32::<LittleEndian>().unwrap());
         let packet_max = cursor.read_u32::<LittleEndian>().unwrap();
-        let i_box = cursor.read_u32::<LittleEndian>().unwrap();
-        let phone_mode = cursor.read_u32::<LittleEndian>().unwrap();
+        let i_box = BoxVersion::from(cursor.read_u32::<LittleEndian>().unwrap());
+        let phone_mode = PhoneWorkMode::from(cursor.read_u32::<LittleEndian>().unwrap());
         Opened {
             header,
             width,
@@ -480,36 +501,169 @@ impl Opened {
     }
 }
 
+/// The dongle's own details, sent once after start-up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DongleDetails {
+    #[serde(rename = "HiCar")]
+    pub hi_car: Option<u32>,
+    #[serde(rename = "OemName")]
+    pub oem_name: Option<String>,
+    #[serde(rename = "WiFiChannel")]
+    pub wifi_channel: Option<u32>,
+    #[serde(rename = "boxType")]
+    pub box_type: Option<String>,
+    #[serde(rename = "hwVersion")]
+    pub hw_version: Option<String>,
+    #[serde(rename = "productType")]
+    pub product_type: Option<String>,
+    pub uuid: Option<String>,
+    /// Keys this firmware adds that we do not know about
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// Details of the connected phone, sent with each connection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PhoneDetails {
+    #[serde(rename = "MDLinkType")]
+    pub md_link_type: Option<String>,
+    #[serde(rename = "MDModel")]
+    pub md_model: Option<String>,
+    #[serde(rename = "MDOSVersion")]
+    pub md_os_version: Option<String>,
+    #[serde(rename = "MDLinkVersion")]
+    pub md_link_version: Option<String>,
+    #[serde(rename = "cpuTemp")]
+    pub cpu_temp: Option<f64>,
+    /// Keys this firmware adds that we do not know about
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+const DONGLE_DETAILS_KEYS: [&str; 7] = [
+    "HiCar",
+    "OemName",
+    "WiFiChannel",
+    "boxType",
+    "hwVersion",
+    "productType",
+    "uuid",
+];
+const PHONE_DETAILS_KEYS: [&str; 5] = [
+    "MDLinkType",
+    "MDModel",
+    "MDOSVersion",
+    "MDLinkVersion",
+    "cpuTemp",
+];
+
+/// The JSON the dongle sends as `BoxSettings`. Firmware updates add, drop
+/// and retype keys, so anything that does not fit is kept as `Raw`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, from = "serde_json::Value")]
 pub enum BoxSettings {
-    Type1 {
-        #[serde(rename = "HiCar")]
-        hi_car: u32,
-        #[serde(rename = "OemName")]
-        oem_name: String,
-        #[serde(rename = "WiFiChannel")]
-        wifi_channel: u32,
-        #[serde(rename = "boxType")]
-        box_type: String,
-        #[serde(rename = "hwVersion")]
-        hw_version: String,
-        #[serde(rename = "productType")]
-        product_type: String,
-        uuid: String,
-    },
-    Type2 {
-        #[serde(rename = "MDLinkType")]
-        md_link_type: String,
-        #[serde(rename = "MDModel")]
-        md_model: String,
-        #[serde(rename = "MDOSVersion")]
-        md_os_version: String,
-        #[serde(rename = "MDLinkVersion")]
-        md_link_version: String,
-        #[serde(rename = "cpuTemp")]
-        cpu_temp: f64,
-    },
+    Type1(DongleDetails),
+    Type2(PhoneDetails),
+    Raw(serde_json::Value),
+}
+
+impl From<serde_json::Value> for BoxSettings {
+    fn from(value: serde_json::Value) -> Self {
+        let has_any = |keys: &[&str]| {
+            value
+                .as_object()
+                .is_some_and(|object| keys.iter().any(|key| object.contains_key(*key)))
+        };
+        let parsed = if has_any(&DONGLE_DETAILS_KEYS) {
+            serde_json::from_value(value.clone()).map(BoxSettings::Type1)
+        } else if has_any(&PHONE_DETAILS_KEYS) {
+            serde_json::from_value(value.clone()).map(BoxSettings::Type2)
+        } else {
+            return BoxSettings::Raw(value);
+        };
+        parsed.unwrap_or_else(|e| {
+            warn!("Keeping unexpected box settings as raw JSON: {}", e);
+            BoxSettings::Raw(value)
+        })
+    }
+}
+
+impl BoxSettings {
+    /// Whether these are the dongle's own details rather than the phone's.
+    pub fn is_dongle_details(&self) -> bool {
+        match self {
+            BoxSettings::Type1(_) => true,
+            BoxSettings::Type2(_) => false,
+            BoxSettings::Raw(value) => DONGLE_DETAILS_KEYS
+                .iter()
+                .any(|key| value.get(key).is_some()),
+        }
+    }
+
+    /// The value of `key` as the dongle sent it, typed or not.
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        match self {
+            BoxSettings::Raw(value) => value.get(key).cloned(),
+            _ => serde_json::to_value(self).ok()?.get(key).cloned(),
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Option<String> {
+        match self.get(key)? {
+            serde_json::Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn oem_name(&self) -> Option<String> {
+        self.get_str("OemName")
+    }
+
+    pub fn hw_version(&self) -> Option<String> {
+        self.get_str("hwVersion")
+    }
+
+    pub fn product_type(&self) -> Option<String> {
+        self.get_str("productType")
+    }
+
+    pub fn uuid(&self) -> Option<String> {
+        self.get_str("uuid")
+    }
+
+    /// Also accepts a channel sent as a string.
+    pub fn wifi_channel(&self) -> Option<u32> {
+        match self.get("WiFiChannel")? {
+            serde_json::Value::Number(channel) => channel.as_u64()?.try_into().ok(),
+            serde_json::Value::String(channel) => channel.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn md_link_type(&self) -> Option<String> {
+        self.get_str("MDLinkType")
+    }
+
+    pub fn md_model(&self) -> Option<String> {
+        self.get_str("MDModel")
+    }
+
+    pub fn md_os_version(&self) -> Option<String> {
+        self.get_str("MDOSVersion")
+    }
+
+    pub fn md_link_version(&self) -> Option<String> {
+        self.get_str("MDLinkVersion")
+    }
+
+    /// Also accepts a temperature sent as a string.
+    pub fn cpu_temp(&self) -> Option<f64> {
+        match self.get("cpuTemp")? {
+            serde_json::Value::Number(temp) => temp.as_f64(),
+            serde_json::Value::String(temp) => temp.trim().parse().ok(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -521,8 +675,13 @@ pub struct BoxInfo {
 impl ReadableMessage for BoxInfo {}
 impl BoxInfo {
     pub fn new(header: MessageHeader, data: Vec<u8>) -> Self {
-        let data_string = String::from_utf8(data).unwrap();
-        let settings = serde_json::from_str(&*data_string).unwrap();
+        let settings = match serde_json::from_slice::<serde_json::Value>(&data) {
+            Ok(value) => BoxSettings::from(value),
+            Err(e) => {
+                warn!("Box settings are not JSON: {}", e);
+                BoxSettings::Raw(serde_json::Value::String(text(&data)))
+            }
+        };
 
         BoxInfo { header, settings }
     }
@@ -554,3 +713,116 @@ impl Unknown {
         Unknown { header }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messagetypes::MessageType;
+
+    fn box_settings(json: &str) -> BoxSettings {
+        let header = MessageHeader {
+            length: json.len() as u32,
+            msg_type: MessageType::BoxSettings,
+        };
+        BoxInfo::new(header, json.as_bytes().to_vec()).settings
+    }
+
+    fn paired_list(data: &str) -> Vec<PairedDevice> {
+        let header = MessageHeader {
+            length: data.len() as u32,
+            msg_type: MessageType::BluetoothPairedList,
+        };
+        BluetoothPairedList::new(header, data.as_bytes().to_vec()).devices
+    }
+
+    #[test]
+    fn keeps_unknown_keys_in_extra() {
+        let settings = box_settings(r#"{"uuid":"abc","WiFiChannel":36,"newFeature":{"on":true}}"#);
+        let BoxSettings::Type1(details) = &settings else {
+            panic!("expected dongle details, got {:?}", settings);
+        };
+        assert_eq!(details.extra["newFeature"], serde_json::json!({"on": true}));
+        assert_eq!(
+            settings.get("newFeature"),
+            Some(serde_json::json!({"on": true}))
+        );
+        assert_eq!(settings.uuid().as_deref(), Some("abc"));
+        assert_eq!(settings.wifi_channel(), Some(36));
+    }
+
+    #[test]
+    fn retyped_values_fall_back_to_raw() {
+        let settings = box_settings(r#"{"WiFiChannel":"149","OemName":"Carlinkit"}"#);
+        assert!(matches!(settings, BoxSettings::Raw(_)));
+        assert!(settings.is_dongle_details());
+        assert_eq!(settings.wifi_channel(), Some(149));
+        assert_eq!(settings.oem_name().as_deref(), Some("Carlinkit"));
+
+        let settings = box_settings(r#"{"cpuTemp":" 71.5 ","MDModel":"iPhone"}"#);
+        assert!(matches!(settings, BoxSettings::Raw(_)));
+        assert!(!settings.is_dongle_details());
+        assert_eq!(settings.cpu_temp(), Some(71.5));
+        assert_eq!(settings.md_model().as_deref(), Some("iPhone"));
+    }
+
+    #[test]
+    fn typed_phone_details() {
+        let settings = box_settings(r#"{"cpuTemp":64,"MDLinkType":"CarPlay"}"#);
+        assert!(matches!(settings, BoxSettings::Type2(_)));
+        assert_eq!(settings.cpu_temp(), Some(64.0));
+        assert_eq!(settings.md_link_type().as_deref(), Some("CarPlay"));
+    }
+
+    #[test]
+    fn keeps_non_json_payloads_as_text() {
+        let settings = box_settings("not json\0\0");
+        assert!(matches!(
+            &settings,
+            BoxSettings::Raw(serde_json::Value::String(text)) if text == "not json"
+        ));
+        assert!(!settings.is_dongle_details());
+        assert_eq!(settings.cpu_temp(), None);
+
+        let settings = box_settings("[1, 2]");
+        assert!(matches!(settings, BoxSettings::Raw(_)));
+        assert_eq!(settings.uuid(), None);
+    }
+
+    #[test]
+    fn mixed_dongle_and_phone_keys() {
+        let settings = box_settings(r#"{"uuid":"abc","cpuTemp":55,"MDModel":"Pixel"}"#);
+        assert!(settings.is_dongle_details());
+        assert_eq!(settings.uuid().as_deref(), Some("abc"));
+        assert_eq!(settings.cpu_temp(), Some(55.0));
+        assert_eq!(settings.md_model().as_deref(), Some("Pixel"));
+    }
+
+    #[test]
+    fn parses_paired_devices() {
+        let devices = paired_list("AA:BB:CC:DD:EE:FFMy iPhone\nAA:BB:CC:DD:EE:00 Pixel 8\r\n\0");
+        assert_eq!(
+            devices,
+            vec![
+                PairedDevice {
+                    address: "AA:BB:CC:DD:EE:FF".parse().unwrap(),
+                    name: "My iPhone".to_string(),
+                },
+                PairedDevice {
+                    address: "AA:BB:CC:DD:EE:00".parse().unwrap(),
+                    name: "Pixel 8".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unreadable_paired_devices() {
+        assert!(paired_list("").is_empty());
+        assert!(paired_list("\0\0").is_empty());
+        let devices =
+            paired_list("short\nZZ:BB:CC:DD:EE:FFBad\naa-bb-cc-dd-ee-ffPhone\nAA:BB:CC:DD:EÉ:FF");
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].address.to_string(), "AA:BB:CC:DD:EE:FF");
+        assert_eq!(devices[0].name, "Phone");
+    }
+}
@@ -0,0 +1,149 @@
+use crate::sendable::SendableMessage;
+use log::{debug, error};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// When a scheduled task runs, for inspection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Sends one message after the delay
+    After(Duration),
+    /// Sends a message every period, the first one after a period
+    Every(Duration),
+    /// Runs its own loop
+    Task,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub name: &'static str,
+    pub schedule: Schedule,
+    pub started: Instant,
+    pub finished: bool,
+}
+
+struct ScheduledTask {
+    name: &'static str,
+    schedule: Schedule,
+    started: Instant,
+    handle: JoinHandle<()>,
+}
+
+/// The driver's background tasks, by name. Scheduling a name that is
+/// already taken replaces the old task.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    tasks: Arc<Mutex<Vec<ScheduledTask>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F>(&self, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.insert(name, Schedule::Task, tokio::spawn(future));
+    }
+
+    pub fn after(
+        &self,
+        name: &'static str,
+        delay: Duration,
+        tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+        message: Box<dyn SendableMessage + Send>,
+    ) {
+        let handle = tokio::spawn(async move {
+            time::sleep(delay).await;
+            if tx.send(message).await.is_err() {
+                error!("{} error: send queue closed", name);
+            }
+        });
+        self.insert(name, Schedule::After(delay), handle);
+    }
+
+    pub fn every<F>(
+        &self,
+        name: &'static str,
+        period: Duration,
+        tx: mpsc::Sender<Box<dyn SendableMessage + Send>>,
+        message: F,
+    ) where
+        F: Fn() -> Box<dyn SendableMessage + Send> + Send + 'static,
+    {
+        let handle = tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if tx.send(message()).await.is_err() {
+                    error!("{} error: send queue closed", name);
+                    return;
+                }
+                debug!("Sent scheduled {}", name);
+            }
+        });
+        self.insert(name, Schedule::Every(period), handle);
+    }
+
+    pub fn cancel(&self, name: &'static str) {
+        let cancelled: Vec<ScheduledTask> = {
+            let mut tasks = self.tasks.lock().unwrap();
+            let (cancelled, kept) = tasks.drain(..).partition(|task| task.name == name);
+            *tasks = kept;
+            cancelled
+        };
+        for task in cancelled {
+            task.handle.abort();
+        }
+    }
+
+    pub fn cancel_all(&self) {
+        let cancelled = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in cancelled {
+            debug!("Cancelling {}", task.name);
+            task.handle.abort();
+        }
+    }
+
+    /// Whether `name` is scheduled and has not run to completion yet.
+    pub fn is_pending(&self, name: &'static str) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|task| task.name == name && !task.handle.is_finished())
+    }
+
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|task| TaskInfo {
+                name: task.name,
+                schedule: task.schedule,
+                started: task.started,
+                finished: task.handle.is_finished(),
+            })
+            .collect()
+    }
+
+    fn insert(&self, name: &'static str, schedule: Schedule, handle: JoinHandle<()>) {
+        self.cancel(name);
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.handle.is_finished());
+        tasks.push(ScheduledTask {
+            name,
+            schedule,
+            started: Instant::now(),
+            handle,
+        });
+    }
+}
@@ -1,12 +1,12 @@
 use crate::commands::CommandMapping;
-use crate::driver::DongleConfig;
+use crate::driver::{DongleConfig, DriverError};
 use crate::message::MessageHeader;
-use crate::messagetypes::MessageType;
+use crate::messagetypes::{MessageType, PhoneType};
 use byteorder::{LittleEndian, WriteBytesExt};
 use futures::AsyncWriteExt;
 use futures_lite::future::block_on;
 use log::{error, info};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait SendableMessage {
@@ -24,6 +24,14 @@ pub trait SendableMessage {
         // info!("msgtype: {:?}, header: {:?}", self.message_type(), header);
         header
     }
+    /// Largest bulk transfer to send the serialized message in, one
+    /// transfer when `None`.
+    fn transfer_size(&self) -> Option<usize> {
+        None
+    }
+    /// Called by the send loop with the number of transfers it took, or why
+    /// it failed.
+    fn sent(&self, _result: Result<usize, &DriverError>) {}
 }
 
 #[derive(Clone, Debug)]
@@ -202,6 +210,31 @@ impl SendFile {
         Self { content, file_name }
     }
 
+    /// `value` as a little-endian `u32`.
+    pub fn number(value: u32, path: impl Into<String>) -> Self {
+        let mut content = Vec::with_capacity(4);
+        content.write_u32::<LittleEndian>(value).unwrap();
+        Self::new(content, path.into())
+    }
+
+    /// `value` as the number 0 or 1.
+    pub fn boolean(value: bool, path: impl Into<String>) -> Self {
+        Self::number(value as u32, path)
+    }
+
+    /// `value` as UTF-8, without a terminating NUL.
+    pub fn string(value: &str, path: impl Into<String>) -> Self {
+        Self::new(value.as_bytes().to_vec(), path.into())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
     fn get_file_name(&self) -> Vec<u8> {
         let mut name = self.file_name.clone();
         name.push('\0');
@@ -226,14 +259,14 @@ impl SendableMessage for SendFile {
         buf.write_u32::<LittleEndian>(new_file_name.len() as u32)
             .unwrap();
         block_on(buf.write_all(&*new_file_name)).unwrap();
-        buf.write_u32::<LittleEndian>(content_length.len() as u32)
-            .unwrap();
+        block_on(buf.write_all(&content_length)).unwrap();
         block_on(buf.write_all(&self.content)).unwrap();
         buf
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FileAddress {
     Dpi,
     NightMode,
@@ -249,7 +282,26 @@ pub enum FileAddress {
 }
 
 impl FileAddress {
-    fn as_str(&self) -> &'static str {
+    pub const ALL: [FileAddress; 11] = [
+        FileAddress::Dpi,
+        FileAddress::NightMode,
+        FileAddress::HandDriveMode,
+        FileAddress::ChargeMode,
+        FileAddress::BoxName,
+        FileAddress::OemIcon,
+        FileAddress::AirplayConfig,
+        FileAddress::Icon120,
+        FileAddress::Icon180,
+        FileAddress::Icon250,
+        FileAddress::AndroidWorkMode,
+    ];
+
+    /// The known file at `path`, if any.
+    pub fn from_path(path: &str) -> Option<FileAddress> {
+        Self::ALL.into_iter().find(|file| file.as_str() == path)
+    }
+
+    pub fn as_str(&self) -> &'static str {
         match self {
             FileAddress::Dpi => "/tmp/screen_dpi",
             FileAddress::NightMode => "/tmp/night_mode",
@@ -272,9 +324,7 @@ pub struct SendNumber {
 
 impl SendNumber {
     pub fn new(content: u32, file: FileAddress) -> Self {
-        let mut message = Vec::with_capacity(4);
-        message.write_u32::<LittleEndian>(content).unwrap();
-        let inner = SendFile::new(message, file.as_str().to_string());
+        let inner = SendFile::number(content, file.as_str());
         Self { inner }
     }
 }
@@ -317,8 +367,7 @@ impl SendString {
         if content.len() > 16 {
             error!("string too long");
         }
-        let message = content.into_bytes();
-        let inner = SendFile::new(message, file.as_str().to_string());
+        let inner = SendFile::string(&content, file.as_str());
         Self { inner }
     }
 }
@@ -364,12 +413,13 @@ impl SendableMessage for SendOpen {
         buf.write_u32::<LittleEndian>(self.config.width).unwrap();
         buf.write_u32::<LittleEndian>(self.config.height).unwrap();
         buf.write_u32::<LittleEndian>(self.config.fps).unwrap();
-        buf.write_u32::<LittleEndian>(self.config.format).unwrap();
+        buf.write_u32::<LittleEndian>(self.config.format.into())
+            .unwrap();
         buf.write_u32::<LittleEndian>(self.config.packet_max)
             .unwrap();
-        buf.write_u32::<LittleEndian>(self.config.i_box_version)
+        buf.write_u32::<LittleEndian>(self.config.i_box_version.into())
             .unwrap();
-        buf.write_u32::<LittleEndian>(self.config.phone_work_mode)
+        buf.write_u32::<LittleEndian>(self.config.phone_work_mode.into())
             .unwrap();
         buf
     }
@@ -407,11 +457,13 @@ impl SendableMessage for SendBoxSettings {
             android_auto_size_h: u32,
         }
 
+        let (android_auto_size_w, android_auto_size_h) =
+            self.config.video_size(PhoneType::AndroidAuto);
         let payload = BoxSettingsPayload {
             media_delay: self.config.media_delay,
             sync_time: self.sync_time.unwrap_or_else(Self::get_current_time_ms),
-            android_auto_size_w: self.config.width,
-            android_auto_size_h: self.config.height,
+            android_auto_size_w,
+            android_auto_size_h,
         };
 
         serde_json::to_vec(&payload).unwrap()
@@ -455,12 +507,12 @@ impl SendIconConfig {
     pub fn new(config: IconConfig) -> Self {
         let mut value_map = vec![
             ("oemIconVisible", "1"),
-            ("name", "AutoBox"),
-            ("model", "Magic-Car-Link-1.00"),
+            ("name", config.name.as_str()),
+            ("model", config.model.as_str()),
             ("oemIconPath", FileAddress::OemIcon.as_str()),
         ];
 
-        if let Some(label) = config.label {
+        if let Some(label) = config.label.as_deref() {
             value_map.push(("oemIconLabel", label));
         }
 
@@ -479,6 +531,12 @@ impl SendIconConfig {
     }
 }
 
+impl From<SendIconConfig> for SendFile {
+    fn from(message: SendIconConfig) -> Self {
+        message.inner
+    }
+}
+
 impl SendableMessage for SendIconConfig {
     fn message_type(&self) -> MessageType {
         self.inner.message_type()
@@ -488,9 +546,21 @@ impl SendableMessage for SendIconConfig {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct IconConfig {
-    pub label: Option<&'static str>,
+    pub label: Option<String>,
+    pub name: String,
+    pub model: String,
+}
+
+impl Default for IconConfig {
+    fn default() -> Self {
+        Self {
+            label: None,
+            name: "AutoBox".to_string(),
+            model: "Magic-Car-Link-1.00".to_string(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,211 @@
+use crate::branding::{Branding, BrandingConfig};
+use crate::commands::CommandMapping;
+use crate::driver::{DongleConfig, DriverError, MicType};
+use crate::filepush::PushFile;
+use crate::messagetypes::PhoneType;
+use crate::sendable::*;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// What a single start-up step sends to the dongle. Most actions take their
+/// value from the active `DongleConfig`; the rest carry it themselves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitAction {
+    Dpi,
+    Open,
+    NightMode,
+    HandDrive,
+    ChargeMode,
+    BoxName,
+    BoxSettings,
+    AndroidWorkMode,
+    PreferredPhone,
+    /// The icons and airplay config of `branding`
+    Branding,
+    WifiBand,
+    Mic,
+    AudioTransfer,
+    Command(CommandMapping),
+    Icon(Option<String>),
+    Number(FileAddress, u32),
+    Boolean(FileAddress, bool),
+    String(FileAddress, String),
+}
+
+impl InitAction {
+    /// Builds the messages for this action. Returns none when the config
+    /// leaves the setting unset, in which case the step is skipped. Branding
+    /// is cosmetic, so a bad icon is logged and skipped as well.
+    pub async fn messages(
+        &self,
+        config: &DongleConfig,
+    ) -> Result<Vec<Box<dyn SendableMessage + Send>>, DriverError> {
+        // Writes to firmware files take the push path, for its path check
+        // and packet_max transfers
+        let files = match self {
+            InitAction::Branding => match &config.branding {
+                Some(branding) => match Branding::load_async(branding).await {
+                    Ok(branding) => branding.files(),
+                    Err(e) => {
+                        error!("Skipping branding: {}", e);
+                        Vec::new()
+                    }
+                },
+                None => Vec::new(),
+            },
+            InitAction::PreferredPhone => {
+                let bluetooth = &config.bluetooth;
+                match (bluetooth.preferred_phone, &bluetooth.preferred_phone_file) {
+                    (Some(address), Some(path)) => {
+                        vec![SendFile::string(&address.to_string(), path)]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            _ => return Ok(self.message(config).into_iter().collect()),
+        };
+        files
+            .into_iter()
+            .map(|file| {
+                PushFile::queued(file, config)
+                    .map(|push| Box::new(push) as Box<dyn SendableMessage + Send>)
+            })
+            .collect()
+    }
+
+    fn message(&self, config: &DongleConfig) -> Option<Box<dyn SendableMessage + Send>> {
+        use CommandMapping::*;
+        let message: Box<dyn SendableMessage + Send> = match self {
+            InitAction::Dpi => Box::new(SendNumber::new(config.dpi, FileAddress::Dpi)),
+            InitAction::Open => Box::new(SendOpen::new(config.clone())),
+            InitAction::NightMode => {
+                Box::new(SendBoolean::new(config.night_mode, FileAddress::NightMode))
+            }
+            InitAction::HandDrive => Box::new(SendNumber::new(
+                config.hand as u32,
+                FileAddress::HandDriveMode,
+            )),
+            InitAction::ChargeMode => Box::new(SendBoolean::new(
+                config.charge_mode,
+                FileAddress::ChargeMode,
+            )),
+            InitAction::BoxName => Box::new(SendString::new(
+                config.box_name.clone(),
+                FileAddress::BoxName,
+            )),
+            InitAction::BoxSettings => Box::new(SendBoxSettings::new(config.clone(), None)),
+            InitAction::AndroidWorkMode => Box::new(SendBoolean::new(
+                config.android_work_mode?,
+                FileAddress::AndroidWorkMode,
+            )),
+            InitAction::WifiBand => Box::new(SendCommand {
+                value: config.wifi_type.command(),
+            }),
+            InitAction::Mic => Box::new(SendCommand {
+                value: match config.mic_type {
+                    MicType::Box => BoxMic,
+                    MicType::Os => Mic,
+                },
+            }),
+            InitAction::AudioTransfer => Box::new(SendCommand {
+                value: if config.audio_transfer_mode {
+                    AudioTransferOn
+                } else {
+                    AudioTransferOff
+                },
+            }),
+            InitAction::Branding | InitAction::PreferredPhone => return None,
+            InitAction::Command(value) => Box::new(SendCommand { value: *value }),
+            InitAction::Icon(label) => {
+                let mut icon_config = config
+                    .branding
+                    .as_ref()
+                    .map(BrandingConfig::icon_config)
+                    .unwrap_or_default();
+                if label.is_some() {
+                    icon_config.label = label.clone();
+                }
+                Box::new(SendIconConfig::new(icon_config))
+            }
+            InitAction::Number(file, value) => Box::new(SendNumber::new(*value, *file)),
+            InitAction::Boolean(file, value) => Box::new(SendBoolean::new(*value, *file)),
+            InitAction::String(file, value) => Box::new(SendString::new(value.clone(), *file)),
+        };
+        Some(message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InitStep {
+    pub action: InitAction,
+    /// Milliseconds to wait before sending this step
+    #[serde(default)]
+    pub delay: u32,
+}
+
+impl InitStep {
+    pub fn new(action: InitAction) -> Self {
+        Self { action, delay: 0 }
+    }
+
+    pub fn delayed(action: InitAction, delay: u32) -> Self {
+        Self { action, delay }
+    }
+}
+
+/// The order the dongle firmware we test against expects.
+pub fn default_init_sequence(config: &DongleConfig) -> Vec<InitStep> {
+    vec![
+        InitStep::new(InitAction::Dpi),
+        InitStep::new(InitAction::Open),
+        InitStep::new(InitAction::NightMode),
+        InitStep::new(InitAction::HandDrive),
+        InitStep::new(InitAction::ChargeMode),
+        InitStep::new(InitAction::BoxName),
+        InitStep::new(InitAction::Branding),
+        InitStep::new(InitAction::BoxSettings),
+        InitStep::new(InitAction::Command(CommandMapping::WifiEnable)),
+        InitStep::new(InitAction::WifiBand),
+        InitStep::new(InitAction::Mic),
+        InitStep::new(InitAction::AudioTransfer),
+        InitStep::new(InitAction::AndroidWorkMode),
+        InitStep::new(InitAction::PreferredPhone),
+        InitStep::delayed(
+            InitAction::Command(CommandMapping::WifiConnect),
+            config.wifi_connect_delay,
+        ),
+    ]
+}
+
+/// What has to be resent to move the dongle from the `applied` settings to
+/// `target` once a phone of `phone_type` is plugged in.
+pub fn profile_actions(
+    applied: &DongleConfig,
+    target: &DongleConfig,
+    phone_type: PhoneType,
+) -> Vec<InitAction> {
+    let mut actions = Vec::new();
+    if applied.dpi != target.dpi {
+        actions.push(InitAction::Dpi);
+    }
+    // Android Auto takes its resolution from the box settings instead
+    if phone_type != PhoneType::AndroidAuto
+        && (applied.width, applied.height, applied.fps) != (target.width, target.height, target.fps)
+    {
+        actions.push(InitAction::Open);
+    }
+    if applied.media_delay != target.media_delay
+        || applied.video_size(PhoneType::AndroidAuto) != target.video_size(PhoneType::AndroidAuto)
+    {
+        actions.push(InitAction::BoxSettings);
+    }
+    if applied.mic_type != target.mic_type {
+        actions.push(InitAction::Mic);
+    }
+    if applied.audio_transfer_mode != target.audio_transfer_mode {
+        actions.push(InitAction::AudioTransfer);
+    }
+    actions
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Degrees a reading has to drop below a threshold before the level goes
+/// back down, so a dongle hovering at a threshold does not flood events.
+const HYSTERESIS: f64 = 2.0;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemperatureConfig {
+    /// °C at which the dongle is reported as running hot
+    pub warning: u32,
+    /// °C at which the dongle is about to throttle or drop the link
+    pub critical: u32,
+    /// Readings kept in the history
+    pub history_length: usize,
+}
+
+impl Default for TemperatureConfig {
+    fn default() -> Self {
+        Self {
+            warning: 75,
+            critical: 90,
+            history_length: 120,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TemperatureLevel {
+    Normal,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemperatureReading {
+    pub celsius: f64,
+    pub time: SystemTime,
+}
+
+/// The dongle's CPU temperature as reported in its box settings.
+#[derive(Debug)]
+pub struct TemperatureMonitor {
+    config: TemperatureConfig,
+    latest: Option<TemperatureReading>,
+    history: VecDeque<TemperatureReading>,
+    level: TemperatureLevel,
+}
+
+impl Default for TemperatureMonitor {
+    fn default() -> Self {
+        Self::new(TemperatureConfig::default())
+    }
+}
+
+impl TemperatureMonitor {
+    pub fn new(config: TemperatureConfig) -> Self {
+        Self {
+            config,
+            latest: None,
+            history: VecDeque::new(),
+            level: TemperatureLevel::Normal,
+        }
+    }
+
+    /// Adds a reading. Returns the new level if it changed.
+    pub fn record(&mut self, celsius: f64) -> Option<TemperatureLevel> {
+        let reading = TemperatureReading {
+            celsius,
+            time: SystemTime::now(),
+        };
+        self.latest = Some(reading);
+        self.history.push_back(reading);
+        while self.history.len() > self.config.history_length {
+            self.history.pop_front();
+        }
+
+        let level = self.level_for(celsius);
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    fn level_for(&self, celsius: f64) -> TemperatureLevel {
+        let threshold = |level: TemperatureLevel, degrees: u32| {
+            // Leaving a level takes the hysteresis margin, entering does not
+            if self.level >= level {
+                degrees as f64 - HYSTERESIS
+            } else {
+                degrees as f64
+            }
+        };
+        if celsius >= threshold(TemperatureLevel::Critical, self.config.critical) {
+            TemperatureLevel::Critical
+        } else if celsius >= threshold(TemperatureLevel::Warning, self.config.warning) {
+            TemperatureLevel::Warning
+        } else {
+            TemperatureLevel::Normal
+        }
+    }
+
+    pub fn level(&self) -> TemperatureLevel {
+        self.level
+    }
+
+    pub fn latest(&self) -> Option<TemperatureReading> {
+        self.latest
+    }
+
+    /// Oldest first.
+    pub fn history(&self) -> Vec<TemperatureReading> {
+        self.history.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_rise_at_the_thresholds_and_fall_below_the_margin() {
+        use TemperatureLevel::*;
+        let mut monitor = TemperatureMonitor::default();
+        for (celsius, change, level) in [
+            (70.0, None, Normal),
+            (75.0, Some(Warning), Warning),
+            (73.0, None, Warning),
+            (89.9, None, Warning),
+            (90.0, Some(Critical), Critical),
+            (88.0, None, Critical),
+            (87.9, Some(Warning), Warning),
+            (89.9, None, Warning),
+            (73.0, None, Warning),
+            (72.9, Some(Normal), Normal),
+            (74.9, None, Normal),
+        ] {
+            assert_eq!(monitor.record(celsius), change, "at {} °C", celsius);
+            assert_eq!(monitor.level(), level, "at {} °C", celsius);
+        }
+    }
+
+    #[test]
+    fn levels_can_skip_warning() {
+        let mut monitor = TemperatureMonitor::default();
+        assert_eq!(monitor.record(95.0), Some(TemperatureLevel::Critical));
+        assert_eq!(monitor.record(40.0), Some(TemperatureLevel::Normal));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_readings() {
+        let mut monitor = TemperatureMonitor::new(TemperatureConfig {
+            history_length: 3,
+            ..Default::default()
+        });
+        assert!(monitor.latest().is_none());
+        for celsius in [50.0, 51.0, 52.0, 53.0, 54.0] {
+            monitor.record(celsius);
+        }
+        let history: Vec<f64> = monitor
+            .history()
+            .iter()
+            .map(|reading| reading.celsius)
+            .collect();
+        assert_eq!(history, vec![52.0, 53.0, 54.0]);
+        assert_eq!(monitor.latest().map(|reading| reading.celsius), Some(54.0));
+    }
+}
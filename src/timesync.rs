@@ -0,0 +1,50 @@
+use crate::driver::DongleHandle;
+use log::{error, info};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Drift between the wall clock and the monotonic clock that counts as a jump
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Milliseconds since the Unix epoch, negative before it.
+pub fn unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+/// Resends the time to the dongle whenever the system clock jumps, for
+/// example once NTP or GPS time arrives, and every `interval` if set.
+pub async fn run_time_sync(handle: DongleHandle, interval: Option<Duration>) {
+    let mut check = time::interval(CLOCK_CHECK_INTERVAL);
+    let mut last_instant = Instant::now();
+    let mut last_time = SystemTime::now();
+    let mut last_sync = Instant::now();
+    // The start-up sequence already sent the time
+    check.tick().await;
+    loop {
+        check.tick().await;
+        let (now_instant, now_time) = (Instant::now(), SystemTime::now());
+        let expected = unix_millis(last_time) + (now_instant - last_instant).as_millis() as i64;
+        let drift = (unix_millis(now_time) - expected).unsigned_abs();
+        last_instant = now_instant;
+        last_time = now_time;
+
+        let jumped = drift > CLOCK_JUMP_THRESHOLD.as_millis() as u64;
+        let due = interval.is_some_and(|interval| now_instant - last_sync >= interval);
+        if !jumped && !due {
+            continue;
+        }
+        if jumped {
+            info!("System clock jumped by {} ms, resyncing dongle time", drift);
+            handle.clock_jumped();
+        }
+        last_sync = now_instant;
+        if let Err(e) = handle.sync_time().await {
+            error!("Time sync error: {}", e);
+            return;
+        }
+    }
+}
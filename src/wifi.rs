@@ -0,0 +1,214 @@
+use crate::commands::CommandMapping;
+use crate::driver::WifiType;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// What to do when the dongle fails to connect the phone over Wi-Fi.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WifiRetryConfig {
+    /// `WifiConnect` attempts after the first one, 0 disables retrying
+    pub max_retries: u32,
+    /// Milliseconds between a failure and the next attempt
+    pub retry_delay: u32,
+    /// Failures on 5 GHz before switching to 2.4 GHz, never when unset
+    pub fallback_after: Option<u32>,
+}
+
+impl Default for WifiRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            retry_delay: 2000,
+            fallback_after: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WifiEvent {
+    /// `WifiConnect` attempt number `attempt` follows after the dongle
+    /// reported `reason`
+    Retrying {
+        attempt: u32,
+        band: WifiType,
+        fell_back: bool,
+        reason: CommandMapping,
+    },
+    Connected {
+        attempts: u32,
+    },
+    GaveUp {
+        attempts: u32,
+        reason: CommandMapping,
+    },
+}
+
+/// Tracks the Wi-Fi connection attempts of one session.
+#[derive(Debug)]
+pub struct WifiRetry {
+    policy: WifiRetryConfig,
+    band: WifiType,
+    failures: u32,
+}
+
+impl WifiRetry {
+    pub fn new(policy: WifiRetryConfig, band: WifiType) -> Self {
+        Self {
+            policy,
+            band,
+            failures: 0,
+        }
+    }
+
+    /// Whether the dongle reports a failed or lost Wi-Fi connection.
+    pub fn is_failure(command: CommandMapping) -> bool {
+        matches!(
+            command,
+            CommandMapping::ConnectDeviceFailed
+                | CommandMapping::DeviceNotFound
+                | CommandMapping::WifiDisconnected
+        )
+    }
+
+    pub fn band(&self) -> WifiType {
+        self.band
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_millis(self.policy.retry_delay as u64)
+    }
+
+    pub fn on_failure(&mut self, reason: CommandMapping) -> WifiEvent {
+        self.failures += 1;
+        if self.failures > self.policy.max_retries {
+            return WifiEvent::GaveUp {
+                attempts: self.failures,
+                reason,
+            };
+        }
+
+        let fell_back = self.band == WifiType::Ghz5
+            && self
+                .policy
+                .fallback_after
+                .is_some_and(|fallback_after| self.failures >= fallback_after);
+        if fell_back {
+            self.band = WifiType::Ghz2_4;
+        }
+        WifiEvent::Retrying {
+            attempt: self.failures + 1,
+            band: self.band,
+            fell_back,
+            reason,
+        }
+    }
+
+    pub fn on_connected(&mut self) -> WifiEvent {
+        let attempts = self.failures + 1;
+        self.failures = 0;
+        WifiEvent::Connected { attempts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REASON: CommandMapping = CommandMapping::ConnectDeviceFailed;
+
+    fn retry(max_retries: u32, fallback_after: Option<u32>) -> WifiRetry {
+        WifiRetry::new(
+            WifiRetryConfig {
+                max_retries,
+                retry_delay: 0,
+                fallback_after,
+            },
+            WifiType::Ghz5,
+        )
+    }
+
+    fn retrying(attempt: u32, band: WifiType, fell_back: bool) -> WifiEvent {
+        WifiEvent::Retrying {
+            attempt,
+            band,
+            fell_back,
+            reason: REASON,
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        // The fallback would only come with the failure that gives up
+        let mut wifi = retry(2, Some(3));
+        assert_eq!(wifi.on_failure(REASON), retrying(2, WifiType::Ghz5, false));
+        assert_eq!(wifi.on_failure(REASON), retrying(3, WifiType::Ghz5, false));
+        assert_eq!(
+            wifi.on_failure(REASON),
+            WifiEvent::GaveUp {
+                attempts: 3,
+                reason: REASON
+            }
+        );
+        assert_eq!(wifi.band(), WifiType::Ghz5);
+    }
+
+    #[test]
+    fn zero_retries_gives_up_on_the_first_failure() {
+        let mut wifi = retry(0, Some(1));
+        assert_eq!(
+            wifi.on_failure(REASON),
+            WifiEvent::GaveUp {
+                attempts: 1,
+                reason: REASON
+            }
+        );
+        assert_eq!(wifi.band(), WifiType::Ghz5);
+    }
+
+    #[test]
+    fn falls_back_once_after_fallback_after_failures() {
+        let mut wifi = retry(5, Some(2));
+        assert_eq!(wifi.on_failure(REASON), retrying(2, WifiType::Ghz5, false));
+        assert_eq!(wifi.on_failure(REASON), retrying(3, WifiType::Ghz2_4, true));
+        assert_eq!(
+            wifi.on_failure(REASON),
+            retrying(4, WifiType::Ghz2_4, false)
+        );
+        assert_eq!(wifi.band(), WifiType::Ghz2_4);
+    }
+
+    #[test]
+    fn no_fallback_when_unset_or_already_on_2_4_ghz() {
+        let mut wifi = retry(3, None);
+        for attempt in 2..=4 {
+            assert_eq!(
+                wifi.on_failure(REASON),
+                retrying(attempt, WifiType::Ghz5, false)
+            );
+        }
+
+        let mut wifi = WifiRetry::new(
+            WifiRetryConfig {
+                max_retries: 3,
+                retry_delay: 0,
+                fallback_after: Some(1),
+            },
+            WifiType::Ghz2_4,
+        );
+        assert_eq!(
+            wifi.on_failure(REASON),
+            retrying(2, WifiType::Ghz2_4, false)
+        );
+    }
+
+    #[test]
+    fn connecting_resets_the_count() {
+        let mut wifi = retry(1, None);
+        wifi.on_failure(REASON);
+        assert_eq!(wifi.on_connected(), WifiEvent::Connected { attempts: 2 });
+        assert_eq!(wifi.on_failure(REASON), retrying(2, WifiType::Ghz5, false));
+        assert_eq!(wifi.on_connected(), WifiEvent::Connected { attempts: 2 });
+        assert_eq!(wifi.on_connected(), WifiEvent::Connected { attempts: 1 });
+    }
+}